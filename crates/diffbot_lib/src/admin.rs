@@ -0,0 +1,405 @@
+use crate::job::{
+    tracker::{self, JobEntry, JobResult},
+    types::Job,
+};
+use actix_web::{
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorNotFound, InternalError},
+    http::header,
+    web, HttpRequest, HttpResponse,
+};
+use base64::Engine;
+use eyre::Result;
+use sha2::{Digest, Sha256};
+
+type Requeue = Box<dyn Fn(Job) -> Result<()> + Send + Sync>;
+type Cleanup = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// What the admin endpoints need from the bot, register it as app data next to [`scope`]
+pub struct Admin {
+    name: &'static str,
+    token: Option<String>,
+    requeue: Requeue,
+    cleanup: Option<Cleanup>,
+}
+
+impl Admin {
+    /// The endpoints are disabled if there is no token. `requeue` sends the job to the job queue.
+    pub fn new(
+        name: &'static str,
+        token: Option<String>,
+        requeue: impl Fn(Job) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name,
+            token,
+            requeue: Box::new(requeue),
+            cleanup: None,
+        }
+    }
+
+    pub fn with_cleanup(
+        mut self,
+        cleanup: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.cleanup = Some(Box::new(cleanup));
+        self
+    }
+
+    /// Accepts the token as a bearer token or as the password of basic auth, for browsers
+    fn authorize(&self, req: &HttpRequest) -> actix_web::Result<()> {
+        let Some(ref token) = self.token else {
+            return Err(ErrorNotFound("Admin endpoints are disabled"));
+        };
+
+        let provided = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                if let Some(bearer) = value.strip_prefix("Bearer ") {
+                    return Some(bearer.to_owned());
+                }
+                let basic = base64::engine::general_purpose::STANDARD
+                    .decode(value.strip_prefix("Basic ")?)
+                    .ok()?;
+                let (_, password) = std::str::from_utf8(&basic).ok()?.split_once(':')?;
+                Some(password.to_owned())
+            });
+
+        // Comparing digests keeps the comparison from leaking the token
+        match provided {
+            Some(provided) if Sha256::digest(provided) == Sha256::digest(token) => Ok(()),
+            _ => Err(InternalError::from_response(
+                "Unauthorized",
+                HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"diffbot admin\""))
+                    .finish(),
+            )
+            .into()),
+        }
+    }
+
+    /// Browsers send cached basic auth along with forms posted from other sites,
+    /// so requests that change something also have to come from the admin page itself
+    fn authorize_change(&self, req: &HttpRequest) -> actix_web::Result<()> {
+        self.authorize(req)?;
+
+        let get = |name: header::HeaderName| {
+            req.headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap_or_default())
+        };
+        if let Some(site) = get(header::HeaderName::from_static("sec-fetch-site")) {
+            if site != "same-origin" && site != "none" {
+                return Err(ErrorForbidden("Cross-site requests are not allowed"));
+            }
+        }
+        if let Some(origin) = get(header::ORIGIN) {
+            let origin_host = origin.split_once("://").map(|(_, host)| host);
+            if origin_host != Some(req.connection_info().host()) {
+                return Err(ErrorForbidden("Cross-origin requests are not allowed"));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn scope() -> actix_web::Scope {
+    web::scope("/admin")
+        .service(page)
+        .service(list_jobs)
+        .service(cancel_job)
+        .service(requeue_job)
+        .service(run_cleanup)
+}
+
+/// Form posts from the admin page go back to it, API clients get an empty response
+fn done(req: &HttpRequest) -> HttpResponse {
+    let from_browser = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if from_browser {
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/admin"))
+            .finish()
+    } else {
+        HttpResponse::NoContent().finish()
+    }
+}
+
+#[actix_web::get("/jobs")]
+async fn list_jobs(req: HttpRequest, admin: web::Data<Admin>) -> actix_web::Result<HttpResponse> {
+    admin.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(tracker::snapshot()))
+}
+
+#[actix_web::post("/jobs/{check_run_id}/cancel")]
+async fn cancel_job(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    check_run_id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    admin.authorize_change(&req)?;
+    let check_run_id = check_run_id.into_inner();
+    let job = tracker::cancel(check_run_id)
+        .ok_or_else(|| ErrorNotFound("No queued or running job with that check run id"))?;
+    tracing::info!("Job {check_run_id} cancelled through the admin endpoints");
+    if let Err(e) = job
+        .check_run
+        .mark_cancelled("The job was cancelled by an administrator.")
+        .await
+    {
+        tracing::error!("Failed to mark check run {check_run_id} as cancelled: {e:?}");
+    }
+    Ok(done(&req))
+}
+
+#[actix_web::post("/jobs/{check_run_id}/requeue")]
+async fn requeue_job(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    check_run_id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    admin.authorize_change(&req)?;
+    let check_run_id = check_run_id.into_inner();
+    let mut job = tracker::retryable(check_run_id)
+        .ok_or_else(|| ErrorNotFound("No failed job with that check run id"))?;
+    // The old run is completed already, GitHub doesn't let it go back to queued
+    job.check_run = job
+        .check_run
+        .recreate()
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("{e:?}")))?;
+    tracker::requeued(check_run_id, &job);
+    let new_check_run_id = job.check_run.id();
+    (admin.requeue)(job).map_err(|e| ErrorInternalServerError(format!("{e:?}")))?;
+    tracing::info!("Job {check_run_id} requeued through the admin endpoints as {new_check_run_id}");
+    Ok(done(&req))
+}
+
+#[actix_web::post("/cleanup")]
+async fn run_cleanup(req: HttpRequest, admin: web::Data<Admin>) -> actix_web::Result<HttpResponse> {
+    admin.authorize_change(&req)?;
+    let cleanup = admin
+        .cleanup
+        .as_ref()
+        .ok_or_else(|| ErrorNotFound("This bot has no cleanup job"))?;
+    cleanup().map_err(|e| ErrorInternalServerError(format!("{e:?}")))?;
+    tracing::info!("Cleanup job queued through the admin endpoints");
+    Ok(done(&req))
+}
+
+#[actix_web::get("")]
+async fn page(req: HttpRequest, admin: web::Data<Admin>) -> actix_web::Result<HttpResponse> {
+    admin.authorize(&req)?;
+    let jobs = tracker::snapshot();
+
+    let cleanup = if admin.cleanup.is_some() {
+        r#"<form method="post" action="/admin/cleanup"><button>Run cleanup job</button></form>"#
+    } else {
+        ""
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            include_str!("admin_template.html"),
+            name = admin.name,
+            cleanup = cleanup,
+            running_count = jobs.running.len(),
+            running = job_table(&jobs.running),
+            queued_count = jobs.queued.len(),
+            queued = job_table(&jobs.queued),
+            recent_count = jobs.recent.len(),
+            recent = job_table(&jobs.recent),
+        )))
+}
+
+fn job_table(jobs: &[JobEntry]) -> String {
+    if jobs.is_empty() {
+        return "<p>None</p>".to_owned();
+    }
+    let rows = jobs
+        .iter()
+        .map(|job| {
+            let result = match job.result {
+                Some(JobResult::Failed(ref error)) => {
+                    format!("<details><summary>failed</summary><pre>{}</pre></details>", escape(error))
+                }
+                Some(ref result) => result.outcome().to_owned(),
+                None => String::new(),
+            };
+            let action = match job.result {
+                None => "cancel",
+                Some(JobResult::Succeeded) => "",
+                Some(_) => "requeue",
+            };
+            let action = if action.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    r#"<form method="post" action="/admin/jobs/{}/{action}"><button>{action}</button></form>"#,
+                    job.check_run_id
+                )
+            };
+            format!(
+                "<tr><td>{}</td><td>#{}</td><td><code>{}</code></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&job.repo),
+                job.pull_request,
+                escape(job.head_sha.get(..7).unwrap_or(&job.head_sha)),
+                job.check_run_id,
+                escape(&match (&job.stage, &job.progress) {
+                    (Some(stage), Some(progress)) => format!("{stage} ({progress})"),
+                    (stage, _) => stage.clone().unwrap_or_default(),
+                }),
+                job.elapsed_secs()
+                    .map(|secs| format!("{secs}s"))
+                    .unwrap_or_default(),
+                result,
+                action,
+            )
+        })
+        .collect::<String>();
+    format!(
+        "<table><tr><th>Repository</th><th>PR</th><th>Head</th><th>Check run</th><th>Stage</th><th>Elapsed</th><th>Result</th><th></th></tr>{rows}</table>"
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    const TOKEN: &str = "secret";
+
+    fn admin(token: Option<&str>) -> Admin {
+        Admin::new("test", token.map(str::to_owned), |_| Ok(()))
+    }
+
+    fn status(result: actix_web::Result<()>) -> Option<StatusCode> {
+        result.err().map(|e| e.as_response_error().status_code())
+    }
+
+    fn request(headers: &[(&'static str, &str)]) -> HttpRequest {
+        let mut request = TestRequest::default().insert_header((header::HOST, "bot.example.com"));
+        for &(name, value) in headers {
+            request = request.insert_header((name, value.to_owned()));
+        }
+        request.to_http_request()
+    }
+
+    fn basic(user_pass: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(user_pass)
+        )
+    }
+
+    #[test]
+    fn disabled_without_token() {
+        let req = request(&[("authorization", "Bearer secret")]);
+        assert_eq!(
+            status(admin(None).authorize(&req)),
+            Some(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[test]
+    fn accepts_bearer_token() {
+        let req = request(&[("authorization", "Bearer secret")]);
+        assert_eq!(status(admin(Some(TOKEN)).authorize(&req)), None);
+    }
+
+    #[test]
+    fn accepts_basic_auth_password() {
+        let req = request(&[("authorization", basic("anyone:secret").as_str())]);
+        assert_eq!(status(admin(Some(TOKEN)).authorize(&req)), None);
+    }
+
+    #[test]
+    fn rejects_wrong_or_missing_token() {
+        for headers in [
+            vec![("authorization", "Bearer wrong".to_owned())],
+            vec![("authorization", basic("secret:wrong"))],
+            vec![("authorization", basic("secret"))],
+            vec![("authorization", "Basic not base64".to_owned())],
+            vec![("authorization", "secret".to_owned())],
+            vec![],
+        ] {
+            let headers = headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect::<Vec<_>>();
+            let req = request(&headers);
+            assert_eq!(
+                status(admin(Some(TOKEN)).authorize(&req)),
+                Some(StatusCode::UNAUTHORIZED),
+                "{headers:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn change_needs_authorization() {
+        let req = request(&[("sec-fetch-site", "same-origin")]);
+        assert_eq!(
+            status(admin(Some(TOKEN)).authorize_change(&req)),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn change_from_admin_page_or_api_client_is_allowed() {
+        for headers in [
+            vec![],
+            vec![("sec-fetch-site", "same-origin")],
+            vec![("sec-fetch-site", "none")],
+            vec![("origin", "https://bot.example.com")],
+            vec![
+                ("sec-fetch-site", "same-origin"),
+                ("origin", "https://bot.example.com"),
+            ],
+        ] {
+            let mut headers = headers;
+            headers.push(("authorization", "Bearer secret"));
+            let req = request(&headers);
+            assert_eq!(
+                status(admin(Some(TOKEN)).authorize_change(&req)),
+                None,
+                "{headers:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn change_from_other_site_is_forbidden() {
+        for headers in [
+            vec![("sec-fetch-site", "cross-site")],
+            vec![("sec-fetch-site", "same-site")],
+            vec![("origin", "https://evil.example.com")],
+            vec![("origin", "null")],
+            vec![
+                ("sec-fetch-site", "same-origin"),
+                ("origin", "https://evil.example.com"),
+            ],
+        ] {
+            let mut headers = headers;
+            headers.push(("authorization", "Bearer secret"));
+            let req = request(&headers);
+            assert_eq!(
+                status(admin(Some(TOKEN)).authorize_change(&req)),
+                Some(StatusCode::FORBIDDEN),
+                "{headers:?}"
+            );
+        }
+    }
+}
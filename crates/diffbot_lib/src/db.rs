@@ -0,0 +1,79 @@
+use eyre::Result;
+use mysql_async::{params, prelude::Queryable, Conn, Pool};
+
+/// Columns added after the `jobs` table was first created
+const ADDED_COLUMNS: [(&str, &str); 2] = [
+    ("retention_days", "INT(11) NULL DEFAULT NULL"),
+    ("installation_id", "BIGINT(20) NULL DEFAULT NULL"),
+];
+
+/// Adds the columns older tables are missing. `ADD COLUMN IF NOT EXISTS` is MariaDB only,
+/// so the schema is looked up first.
+pub async fn add_missing_columns(conn: &mut Conn) -> Result<()> {
+    for (column, definition) in ADDED_COLUMNS {
+        let exists: Option<u8> = conn
+            .exec_first(
+                r"SELECT 1 FROM information_schema.COLUMNS
+                WHERE TABLE_SCHEMA = DATABASE()
+                AND TABLE_NAME = 'jobs'
+                AND COLUMN_NAME = :column",
+                params! {
+                    "column" => column,
+                },
+            )
+            .await?;
+        if exists.is_none() {
+            conn.query_drop(format!(
+                "ALTER TABLE `jobs` ADD COLUMN `{column}` {definition};"
+            ))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// A job of a closed PR that was kept longer than its retention days
+#[derive(Debug)]
+pub struct ExpiredJob {
+    pub check_id: u64,
+    pub repo_id: u64,
+    pub pr_number: u64,
+    pub installation_id: u64,
+}
+
+/// Jobs whose images should be deleted. Jobs without retention days are left alone.
+pub async fn expired_jobs(pool: &Pool) -> Result<Vec<ExpiredJob>> {
+    let mut conn = pool.get_conn().await?;
+    let jobs = conn
+        .query_map(
+            r"SELECT check_id, repo_id, pr_number, installation_id FROM jobs
+            WHERE processed = b'0'
+            AND retention_days IS NOT NULL
+            AND installation_id IS NOT NULL
+            AND merge_date IS NOT NULL
+            AND merge_date < NOW() - INTERVAL retention_days DAY
+            ORDER BY check_id
+            LIMIT 1000",
+            |(check_id, repo_id, pr_number, installation_id)| ExpiredJob {
+                check_id,
+                repo_id,
+                pr_number,
+                installation_id,
+            },
+        )
+        .await?;
+    Ok(jobs)
+}
+
+/// Marks the job's images as deleted
+pub async fn mark_processed(pool: &Pool, check_id: u64) -> Result<()> {
+    let mut conn = pool.get_conn().await?;
+    conn.exec_drop(
+        r"UPDATE jobs SET processed = b'1' WHERE check_id = :check_id",
+        params! {
+            "check_id" => check_id,
+        },
+    )
+    .await?;
+    Ok(())
+}
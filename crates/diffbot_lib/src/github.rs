@@ -1,3 +1,6 @@
+pub mod commands;
 pub mod github_api;
 pub mod github_types;
 pub mod graphql;
+pub mod installation;
+pub mod webhook;
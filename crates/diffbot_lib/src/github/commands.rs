@@ -0,0 +1,14 @@
+/// Returns the arguments of every line in a comment that starts with the bot's prefix
+///
+/// `"/mdb render _maps/foo.dmm z=2"` with the prefix `"/mdb"` gives `["render", "_maps/foo.dmm", "z=2"]`
+pub fn parse_commands<'a>(body: &'a str, prefix: &str) -> Vec<Vec<&'a str>> {
+    body.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            words
+                .next()?
+                .eq_ignore_ascii_case(prefix)
+                .then(|| words.collect())
+        })
+        .collect()
+}
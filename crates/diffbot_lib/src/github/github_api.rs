@@ -1,60 +1,49 @@
-use crate::github::github_types::{CreateCheckRun, Output, RawCheckRun, UpdateCheckRunBuilder};
+use crate::github::github_types::{
+    CollaboratorPermission, Conclusion, CreateCheckRun, Output, PullRequest, RawCheckRun,
+    UpdateCheckRunBuilder,
+};
 use eyre::{Context, Result};
 use octocrab::models::InstallationId;
 use serde::{Deserialize, Serialize};
 use std::{future::Future, pin::Pin};
 
-pub struct GithubEvent(pub String, pub Option<Vec<u8>>);
+/// Event name, signature and delivery id of a webhook
+pub struct GithubEvent(pub String, pub Option<Vec<u8>>, pub Option<String>);
+
+/// Malformed headers are rejected with a 400
+fn header_str(
+    req: &actix_web::HttpRequest,
+    name: &str,
+) -> Result<Option<String>, actix_web::Error> {
+    req.headers()
+        .get(name)
+        .map(|value| {
+            value.to_str().map(str::to_owned).map_err(|_| {
+                actix_web::error::ErrorBadRequest(format!(
+                    "Corrupt {name} header, failed to convert to string"
+                ))
+            })
+        })
+        .transpose()
+}
 
 impl actix_web::FromRequest for GithubEvent {
-    type Error = std::io::Error;
+    type Error = actix_web::Error;
 
     type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
 
     fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
         let req = req.clone();
         Box::pin(async move {
-            let event_header = match req.headers().get("X-Github-Event") {
-                Some(event) => event
-                    .to_str()
-                    .map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Corrupt X-Github-Event header, failed to convert to string",
-                        )
-                    })?
-                    .to_owned(),
-                None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "Missing X-Github-Event header",
-                    ))
-                }
-            };
-            let hmac_header = match req.headers().get("X-Hub-Signature-256") {
-                Some(event) => {
-                    let sig = event.to_str().map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Corrupt X-Hub-Signature-256 header, failed to convert to string",
-                        )
-                    })?;
-
-                    //remove the `sha256=` part
-                    let (_, sig) = sig.split_at(7);
-
-                    let sig_bytes = hex::decode(sig).map_err(|_| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Corrupt X-Hub-Signature-256 header, failed to decode hex string",
-                        )
-                    })?;
-
-                    Some(sig_bytes)
-                }
-                _ => None,
-            };
-            Ok(GithubEvent(event_header, hmac_header))
+            let event_header = header_str(&req, "X-Github-Event")?.ok_or_else(|| {
+                actix_web::error::ErrorBadRequest("Missing X-Github-Event header")
+            })?;
+            let hmac_header = header_str(&req, "X-Hub-Signature-256")?
+                .map(|sig| crate::verify::parse_signature(&sig))
+                .transpose()
+                .map_err(actix_web::error::ErrorBadRequest)?;
+            let delivery_header = header_str(&req, "X-GitHub-Delivery")?;
+            Ok(GithubEvent(event_header, hmac_header, delivery_header))
         })
     }
 }
@@ -75,6 +64,7 @@ impl CheckRun {
         name: Option<&str>,
     ) -> Result<Self> {
         let inst_id = inst_id.into();
+        crate::metrics::github_api_call("create_check_run");
         let result: RawCheckRun = octocrab::instance()
             .installation(inst_id)?
             .post(
@@ -95,11 +85,51 @@ impl CheckRun {
         })
     }
 
+    /// Looks up a check run the bot created before, along with its status
+    pub async fn fetch<I: Into<InstallationId>>(
+        full_repo: &str,
+        id: u64,
+        inst_id: I,
+    ) -> Result<(Self, String)> {
+        let inst_id = inst_id.into();
+        crate::metrics::github_api_call("get_check_run");
+        let result: RawCheckRun = octocrab::instance()
+            .installation(inst_id)?
+            .get(format!("/repos/{full_repo}/check-runs/{id}"), None::<&()>)
+            .await
+            .wrap_err("Getting check run")?;
+
+        Ok((
+            Self {
+                id: result.id,
+                installation_id: inst_id,
+                head_sha: result.head_sha,
+                repo: full_repo.to_owned(),
+            },
+            result.status,
+        ))
+    }
+
     /// Creates a new check run for the same PR
     pub async fn duplicate(&self, name: &str) -> Result<Self> {
         Self::create(&self.repo, &self.head_sha, self.installation_id, Some(name)).await
     }
 
+    /// Creates a new check run with the same name for the same PR, for running a job again after
+    /// its run was completed since completed runs can't be queued again
+    pub async fn recreate(&self) -> Result<Self> {
+        crate::metrics::github_api_call("get_check_run");
+        let current: RawCheckRun = octocrab::instance()
+            .installation(self.installation_id)?
+            .get(
+                format!("/repos/{}/check-runs/{}", self.repo, self.id),
+                None::<&()>,
+            )
+            .await
+            .wrap_err("Getting check run")?;
+        self.duplicate(&current.name).await
+    }
+
     pub async fn rename(&self, name: &str) -> Result<()> {
         self.update(UpdateCheckRunBuilder::default().name(name.to_owned()))
             .await
@@ -148,14 +178,18 @@ impl CheckRun {
     }
 
     pub async fn mark_succeeded(&self, output: Output) -> Result<()> {
+        self.mark_completed(output, Conclusion::Success).await
+    }
+
+    pub async fn mark_completed(&self, output: Output, conclusion: Conclusion) -> Result<()> {
         self.update(
             UpdateCheckRunBuilder::default()
-                .conclusion("success")
+                .conclusion(conclusion.as_str())
                 .completed_at(chrono::Utc::now().to_rfc3339())
                 .output(output),
         )
         .await
-        .wrap_err("Marking check as success")
+        .wrap_err_with(|| format!("Marking check as {}", conclusion.as_str()))
     }
 
     pub async fn mark_skipped(&self, output: Output) -> Result<()> {
@@ -169,6 +203,21 @@ impl CheckRun {
         .wrap_err("Marking check as skipped")
     }
 
+    pub async fn mark_cancelled(&self, reason: &str) -> Result<()> {
+        self.update(
+            UpdateCheckRunBuilder::default()
+                .conclusion("cancelled")
+                .completed_at(chrono::Utc::now().to_rfc3339())
+                .output(Output {
+                    title: "Job cancelled",
+                    summary: reason.to_owned(),
+                    text: "".to_owned(),
+                }),
+        )
+        .await
+        .wrap_err("Marking check as cancelled")
+    }
+
     pub async fn set_output(&self, output: Output) -> Result<()> {
         self.update(UpdateCheckRunBuilder::default().output(output))
             .await
@@ -180,6 +229,7 @@ impl CheckRun {
 
         #[derive(Deserialize)]
         struct Empty {}
+        crate::metrics::github_api_call("update_check_run");
         let _: Empty = octocrab::instance()
             .installation(self.installation_id)?
             .patch(
@@ -199,6 +249,42 @@ impl CheckRun {
     pub fn id(&self) -> u64 {
         self.id
     }
+
+    pub fn head_sha(&self) -> &str {
+        &self.head_sha
+    }
+}
+
+pub async fn get_pull_request<I: Into<InstallationId>>(
+    full_repo: &str,
+    number: u64,
+    inst_id: I,
+) -> Result<PullRequest> {
+    crate::metrics::github_api_call("get_pull_request");
+    octocrab::instance()
+        .installation(inst_id.into())?
+        .get(format!("/repos/{full_repo}/pulls/{number}"), None::<&()>)
+        .await
+        .wrap_err("Getting pull request")
+}
+
+/// Whether the user can push to the repository, which is required to control the bots through comments
+pub async fn has_write_access<I: Into<InstallationId>>(
+    full_repo: &str,
+    user: &str,
+    inst_id: I,
+) -> Result<bool> {
+    crate::metrics::github_api_call("get_collaborator_permission");
+    let permission: CollaboratorPermission = octocrab::instance()
+        .installation(inst_id.into())?
+        .get(
+            format!("/repos/{full_repo}/collaborators/{user}/permission"),
+            None::<&()>,
+        )
+        .await
+        .wrap_err("Getting collaborator permission")?;
+
+    Ok(matches!(permission.permission.as_str(), "admin" | "write"))
 }
 
 /* local test requires commenting out the .installation(...) call in find_content(), a valid github token with access, and the following dep: actix-rt = "2.9.0"
@@ -29,12 +29,30 @@ pub struct Branch {
     pub r#ref: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Label {
+    pub name: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct PullRequest {
     pub number: u64,
     pub head: Branch,
     pub base: Branch,
     pub title: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    /// `open` or `closed`, missing from the pull requests listed in check suites and runs
+    #[serde(default)]
+    pub state: String,
+}
+
+impl PullRequest {
+    pub fn has_label(&self, name: &str) -> bool {
+        self.labels
+            .iter()
+            .any(|label| label.name.eq_ignore_ascii_case(name))
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -53,9 +71,13 @@ pub struct App {
 #[derive(Deserialize, Debug)]
 pub struct RawCheckRun {
     pub id: u64,
+    #[serde(default)]
+    pub name: String,
     pub pull_requests: Vec<PullRequest>,
     pub head_sha: String,
     pub app: App,
+    #[serde(default)]
+    pub status: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -79,6 +101,101 @@ pub struct PullRequestEventPayload {
     pub repository: Repository,
     pub pull_request: PullRequest,
     pub installation: Installation,
+    /// Only present on `labeled` and `unlabeled` actions
+    pub label: Option<Label>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct User {
+    pub login: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Comment {
+    pub body: String,
+    pub user: User,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IssuePullRequest {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Issue {
+    pub number: u64,
+    /// Only present if the issue is a pull request
+    pub pull_request: Option<IssuePullRequest>,
+}
+
+/// A repository as listed in installation events, which leave out most fields
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InstallationRepository {
+    pub id: u64,
+    pub full_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InstallationWithAccount {
+    pub id: u64,
+    /// The user or organization the app is installed on
+    pub account: User,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InstallationEventPayload {
+    pub action: String,
+    pub installation: InstallationWithAccount,
+    /// Only present if the installation is limited to some repositories
+    #[serde(default)]
+    pub repositories: Vec<InstallationRepository>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InstallationRepositoriesEventPayload {
+    pub action: String,
+    pub installation: Installation,
+    #[serde(default)]
+    pub repositories_added: Vec<InstallationRepository>,
+    #[serde(default)]
+    pub repositories_removed: Vec<InstallationRepository>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PingEventPayload {
+    pub zen: String,
+    pub hook: Hook,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Hook {
+    #[serde(rename = "type")]
+    pub hook_type: String,
+    pub app_id: Option<u64>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub active: bool,
+    pub config: HookConfig,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HookConfig {
+    pub content_type: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IssueCommentEventPayload {
+    pub action: String,
+    pub issue: Issue,
+    pub comment: Comment,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CollaboratorPermission {
+    pub permission: String,
 }
 
 #[derive(Serialize, Debug)]
@@ -129,33 +246,61 @@ pub struct UpdateCheckRun {
     pub output: Option<Output>,
 }
 
-pub type CheckOutputs = Vec<Output>;
+/// Conclusion of a check run that finished rendering
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Conclusion {
+    #[default]
+    Success,
+    /// Some files could not be rendered, the rest of the output is still valid
+    Neutral,
+}
+
+impl Conclusion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Neutral => "neutral",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CheckOutputs {
+    pub outputs: Vec<Output>,
+    pub conclusion: Conclusion,
+}
 
 #[derive(Debug)]
 pub struct CheckOutputBuilder {
     title: &'static str,
-    summary: &'static str,
+    summary: String,
     current_text: String,
     outputs: Vec<Output>,
+    conclusion: Conclusion,
 }
 
 impl CheckOutputBuilder {
-    pub fn new(title: &'static str, summary: &'static str) -> Self {
+    pub fn new<S: Into<String>>(title: &'static str, summary: S) -> Self {
         Self {
             title,
-            summary,
+            summary: summary.into(),
             current_text: String::new(),
             outputs: Vec::new(),
+            conclusion: Conclusion::Success,
         }
     }
 
+    pub fn set_conclusion(&mut self, conclusion: Conclusion) {
+        self.conclusion = conclusion;
+    }
+
     pub fn add_text(&mut self, text: &str) {
         self.current_text.push_str(text);
         // Leaving a 5k character safety margin is prob overkill but oh well
         if self.current_text.len() > 60_000 {
             let output = Output {
                 title: self.title,
-                summary: self.summary.to_string(),
+                summary: self.summary.clone(),
                 text: std::mem::take(&mut self.current_text),
             };
             self.outputs.push(output);
@@ -168,16 +313,20 @@ impl CheckOutputBuilder {
             summary,
             current_text,
             mut outputs,
+            conclusion,
         } = self;
 
         if !current_text.is_empty() {
             let output = Output {
                 title,
-                summary: summary.to_string(),
+                summary,
                 text: current_text,
             };
             outputs.push(output);
         }
-        outputs
+        CheckOutputs {
+            outputs,
+            conclusion,
+        }
     }
 }
@@ -114,6 +114,7 @@ pub async fn get_pull_files<I: Into<InstallationId>>(
     let mut ret = vec![];
 
     loop {
+        crate::metrics::github_api_call("graphql_pull_request_files");
         let queried: QueryReturn = crab
             .graphql(&Query {
                 query: format!(
@@ -171,3 +172,102 @@ pub async fn get_pull_files<I: Into<InstallationId>>(
     }
     Ok(ret)
 }
+
+/*
+  Query:
+{
+  repository(owner: "tgstation", name: "tgstation") {
+    f0: object(expression: "<commit sha>:icons/obj/doors.dmi") { ... on Blob { oid byteSize } }
+    f1: object(expression: "<commit sha>:icons/obj/items.dmi") { ... on Blob { oid byteSize } }
+  }
+}
+  Sample Response:
+{
+  "data": {
+    "repository": {
+      "f0": { "oid": "5d0b8e0c5f0c...", "byteSize": 48213 },
+      "f1": null
+    }
+  }
+}
+*/
+
+#[derive(Deserialize)]
+struct BlobQueryReturn {
+    data: Option<BlobData>,
+    #[serde(default)]
+    errors: Vec<QLError>,
+}
+
+#[derive(Deserialize)]
+struct BlobData {
+    repository: std::collections::HashMap<String, Option<BlobNode>>,
+}
+
+#[derive(Deserialize)]
+struct BlobNode {
+    oid: String,
+    #[serde(rename(deserialize = "byteSize"))]
+    byte_size: u64,
+}
+
+/// A file's git blob at some commit
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub sha: String,
+    pub size: u64,
+}
+
+/// Resolves the blob of every given path at the commit, paths that don't exist are left out
+pub async fn get_blobs<I: Into<InstallationId>>(
+    (user, repo): (String, String),
+    installation: I,
+    commit: &str,
+    paths: &[&str],
+) -> Result<std::collections::HashMap<String, BlobInfo>> {
+    let crab = octocrab::instance().installation(installation.into())?;
+
+    let mut ret = std::collections::HashMap::new();
+
+    for chunk in paths.chunks(100) {
+        let objects = chunk
+            .iter()
+            .enumerate()
+            .map(|(index, path)| {
+                format!(
+                    "f{index}: object(expression: \"{commit}:{}\") {{ ... on Blob {{ oid byteSize }} }}",
+                    path.replace('\\', "\\\\").replace('"', "\\\"")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        crate::metrics::github_api_call("graphql_blobs");
+        let queried: BlobQueryReturn = crab
+            .graphql(&Query {
+                query: format!(
+                    "{{ repository(owner: \"{user}\", name: \"{repo}\")
+    {{ {objects} }}
+}}"
+                ),
+            })
+            .await?;
+
+        let Some(data) = queried.data else {
+            return Err(eyre::eyre!("GraphQL error: {:?}", queried.errors));
+        };
+
+        for (index, path) in chunk.iter().enumerate() {
+            if let Some(Some(node)) = data.repository.get(&format!("f{index}")) {
+                ret.insert(
+                    path.to_string(),
+                    BlobInfo {
+                        sha: node.oid.clone(),
+                        size: node.byte_size,
+                    },
+                );
+            }
+        }
+    }
+    Ok(ret)
+}
@@ -0,0 +1,53 @@
+use crate::github::github_types::{
+    InstallationEventPayload, InstallationRepositoriesEventPayload, InstallationRepository,
+};
+use eyre::Result;
+
+/// Repositories the app got access to or lost access to, from an `installation` or
+/// `installation_repositories` event
+#[derive(Debug, Default)]
+pub struct InstallationChange {
+    pub installation: u64,
+    /// Login of the account the app was uninstalled from
+    pub owner: Option<String>,
+    pub added: Vec<InstallationRepository>,
+    pub removed: Vec<InstallationRepository>,
+    /// The whole installation is gone, not only the repositories listed in `removed`
+    pub uninstalled: bool,
+}
+
+pub fn is_installation_event(event: &str) -> bool {
+    event == "installation" || event == "installation_repositories"
+}
+
+/// `None` for actions that don't change which repositories the app can access
+pub fn parse_installation_event(event: &str, payload: &str) -> Result<Option<InstallationChange>> {
+    if event == "installation_repositories" {
+        let payload: InstallationRepositoriesEventPayload = serde_json::from_str(payload)?;
+        return Ok(Some(InstallationChange {
+            installation: payload.installation.id,
+            owner: None,
+            added: payload.repositories_added,
+            removed: payload.repositories_removed,
+            uninstalled: false,
+        }));
+    }
+
+    let payload: InstallationEventPayload = serde_json::from_str(payload)?;
+    let change = match payload.action.as_str() {
+        "created" => InstallationChange {
+            installation: payload.installation.id,
+            added: payload.repositories,
+            ..Default::default()
+        },
+        "deleted" => InstallationChange {
+            installation: payload.installation.id,
+            owner: Some(payload.installation.account.login),
+            removed: payload.repositories,
+            uninstalled: true,
+            ..Default::default()
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(change))
+}
@@ -0,0 +1,96 @@
+use crate::github::github_types::PingEventPayload;
+use eyre::Result;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{LazyLock, Mutex},
+};
+
+/// How many delivery ids are remembered, GitHub lets anyone with access redeliver old webhooks
+const MAX_DELIVERIES: usize = 10_000;
+
+#[derive(Default)]
+struct Deliveries {
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+static DELIVERIES: LazyLock<Mutex<Deliveries>> = LazyLock::new(Default::default);
+
+/// Remembers a delivery, `false` if it was already claimed and should be ignored.
+/// Webhooks without a delivery id are always processed.
+pub fn claim_delivery(delivery: Option<&str>) -> bool {
+    let Some(delivery) = delivery else {
+        return true;
+    };
+    let mut deliveries = DELIVERIES.lock().unwrap();
+    if !deliveries.seen.insert(delivery.to_owned()) {
+        return false;
+    }
+    deliveries.order.push_back(delivery.to_owned());
+    if deliveries.order.len() > MAX_DELIVERIES {
+        if let Some(oldest) = deliveries.order.pop_front() {
+            deliveries.seen.remove(&oldest);
+        }
+    }
+    true
+}
+
+/// Forgets a delivery that failed, so a redelivery of it is processed again
+pub fn release_delivery(delivery: Option<&str>) {
+    let Some(delivery) = delivery else {
+        return;
+    };
+    let mut deliveries = DELIVERIES.lock().unwrap();
+    if deliveries.seen.remove(delivery) {
+        deliveries.order.retain(|seen| seen != delivery);
+    }
+}
+
+/// Checks the webhook a `ping` event was sent for against the app configuration,
+/// errors with everything that is misconfigured
+pub fn check_ping(payload: &str, app_id: u64, required_events: &[&str]) -> Result<()> {
+    let payload: PingEventPayload = serde_json::from_str(payload)?;
+    let hook = payload.hook;
+    tracing::info!("Pinged: {}", payload.zen);
+
+    let mut problems = Vec::new();
+    if hook.hook_type != "App" {
+        problems.push(format!(
+            "Webhook is a {} webhook, it must be set up on the GitHub App",
+            hook.hook_type
+        ));
+    }
+    match hook.app_id {
+        Some(id) if id != app_id => problems.push(format!(
+            "Webhook belongs to app {id}, but the bot is configured for app {app_id}"
+        )),
+        _ => {}
+    }
+    if !hook.active {
+        problems.push("Webhook is not active".to_owned());
+    }
+    if hook.config.content_type.as_deref() != Some("json") {
+        problems.push(format!(
+            "Webhook content type must be json, not {}",
+            hook.config.content_type.as_deref().unwrap_or("unset")
+        ));
+    }
+    if !hook.events.iter().any(|event| event == "*") {
+        let missing: Vec<&str> = required_events
+            .iter()
+            .copied()
+            .filter(|required| !hook.events.iter().any(|event| event == required))
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!(
+                "App is not subscribed to the events: {}",
+                missing.join(", ")
+            ));
+        }
+    }
+
+    if !problems.is_empty() {
+        eyre::bail!("Webhook is misconfigured:\n{}", problems.join("\n"));
+    }
+    Ok(())
+}
@@ -1,2 +1,7 @@
+pub mod limits;
+pub mod reconcile;
+pub mod report;
 pub mod runner;
+pub mod shutdown;
+pub mod tracker;
 pub mod types;
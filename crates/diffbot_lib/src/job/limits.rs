@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// A limit shared by everything a job renders, such as pixels or bytes of output
+#[derive(Debug)]
+pub struct Budget {
+    what: &'static str,
+    limit: u64,
+    used: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl Budget {
+    /// `what` names the unit for messages, e.g. `bytes of output`
+    pub fn new(what: &'static str, limit: u64) -> Self {
+        Self {
+            what,
+            limit,
+            used: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Takes `amount` out of the budget, `false` if there isn't enough left for it
+    pub fn take(&self, amount: u64) -> bool {
+        let taken = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(amount)
+                    .filter(|total| *total <= self.limit)
+            })
+            .is_ok();
+        if !taken {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+        taken
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Error for whatever had to be skipped because the budget ran out
+    pub fn error(&self) -> eyre::Report {
+        LimitReached(format!(
+            "Skipped, the job reached its limit of {} {}",
+            self.limit, self.what
+        ))
+        .into()
+    }
+
+    /// Explains what was left out for the check summary, if anything was
+    pub fn notice(&self) -> Option<String> {
+        self.is_exceeded().then(|| {
+            format!(
+                "This job reached its limit of {} {}, the rest was not rendered.",
+                self.limit, self.what
+            )
+        })
+    }
+}
+
+/// Error for something left out because of a resource limit rather than because it is broken
+#[derive(Debug)]
+pub struct LimitReached(pub String);
+
+impl std::fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LimitReached {}
+
+/// Whether the error, or any error it wraps, is a [`LimitReached`]
+pub fn is_limit_reached(error: &eyre::Report) -> bool {
+    error.chain().any(|cause| cause.is::<LimitReached>())
+}
+
+/// Drops the files past `max_files`, returning a notice for the check summary if any were dropped
+pub fn limit_files<T>(files: &mut Vec<T>, max_files: usize, kind: &str) -> Option<String> {
+    if files.len() <= max_files {
+        return None;
+    }
+    let notice = format!(
+        "This PR changes {} {kind}, only the first {max_files} were rendered.",
+        files.len()
+    );
+    files.truncate(max_files);
+    Some(notice)
+}
@@ -0,0 +1,121 @@
+use crate::{
+    github::{
+        github_api::{get_pull_request, CheckRun},
+        github_types::{Installation, PullRequest, Repository},
+    },
+    job::tracker,
+    metrics,
+};
+use eyre::{Context, Result};
+use octocrab::models::InstallationId;
+use std::collections::HashMap;
+
+/// A check run the bot created that never completed, most likely because the bot crashed
+pub struct StaleCheckRun {
+    pub check_run: CheckRun,
+    pub repository: Repository,
+    pub pull_request: PullRequest,
+    pub installation: Installation,
+}
+
+impl StaleCheckRun {
+    /// Whether the check run is still for the latest commit of an open PR, and worth running again
+    pub fn is_current(&self) -> bool {
+        self.pull_request.state == "open" && self.pull_request.head.sha == self.check_run.head_sha()
+    }
+
+    /// Concludes the check run as cancelled, for runs that are not worth running again
+    pub async fn mark_interrupted(&self) -> Result<()> {
+        self.check_run
+            .mark_cancelled(
+                "Interrupted: the bot restarted while this job was pending. Push a new commit or use the rerun command to try again.",
+            )
+            .await
+    }
+}
+
+/// A job the bot recorded, as `(check_id, repo_id, pr_number, installation_id)`
+pub type Candidate = (u64, u64, u64, u64);
+
+/// Checks the check runs the bot recorded and returns those not completed
+///
+/// Check runs the bot is currently working on, such as restored jobs, are left alone.
+pub async fn find_stale(candidates: &[Candidate]) -> Result<Vec<StaleCheckRun>> {
+    let known = tracker::snapshot();
+    let known: Vec<u64> = known
+        .queued
+        .iter()
+        .chain(known.running.iter())
+        .map(|entry| entry.check_run_id)
+        .collect();
+
+    // Several check runs usually belong to the same repository
+    let mut repositories: HashMap<u64, Option<Repository>> = HashMap::new();
+    let mut stale = Vec::new();
+    for &(check_id, repo_id, pr_number, installation_id) in candidates {
+        if known.contains(&check_id) {
+            continue;
+        }
+        let installation = InstallationId(installation_id);
+
+        let repository = match repositories.get(&repo_id) {
+            Some(repository) => repository.clone(),
+            None => {
+                let repository = match get_repository(repo_id, installation).await {
+                    Ok(repository) => Some(repository),
+                    Err(e) => {
+                        tracing::debug!("Repository {repo_id} is not accessible: {e:?}");
+                        None
+                    }
+                };
+                repositories.insert(repo_id, repository.clone());
+                repository
+            }
+        };
+        let Some(repository) = repository else {
+            tracing::debug!("Skipping check run {check_id}, repository {repo_id} is not installed");
+            continue;
+        };
+        let full_name = repository.full_name();
+
+        let (check_run, status) = match CheckRun::fetch(&full_name, check_id, installation).await {
+            Ok(found) => found,
+            Err(e) => {
+                tracing::warn!("Failed to look up check run {check_id} in {full_name}: {e:?}");
+                continue;
+            }
+        };
+        if status == "completed" {
+            continue;
+        }
+
+        let pull_request = match get_pull_request(&full_name, pr_number, installation).await {
+            Ok(pull_request) => pull_request,
+            Err(e) => {
+                tracing::warn!("Failed to get {full_name}#{pr_number}: {e:?}");
+                continue;
+            }
+        };
+
+        stale.push(StaleCheckRun {
+            check_run,
+            repository,
+            pull_request,
+            installation: Installation {
+                id: installation_id,
+            },
+        });
+    }
+
+    Ok(stale)
+}
+
+/// Looks up a repository through the installation the job was recorded for
+async fn get_repository(repo_id: u64, installation: InstallationId) -> Result<Repository> {
+    metrics::github_api_call("get_repository");
+    octocrab::instance()
+        .installation(installation)?
+        .get(format!("/repositories/{repo_id}"), None::<&()>)
+        .await
+        .wrap_err_with(|| format!("Getting repository {repo_id}"))
+}
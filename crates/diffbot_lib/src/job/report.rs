@@ -0,0 +1,56 @@
+use crate::job::types::Job;
+use eyre::{Context, Result};
+use serde::Serialize;
+use std::time::Instant;
+
+pub const REPORT_FILE_NAME: &str = "report.json";
+
+/// Machine readable counterpart of the check output, stored next to the rendered images
+#[derive(Serialize, Debug)]
+pub struct JobReport<T: Serialize> {
+    pub repository: String,
+    pub pull_request: u64,
+    pub base_sha: String,
+    pub head_sha: String,
+    pub check_run_id: u64,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub duration_ms: Option<u128>,
+    pub files: Vec<T>,
+    pub errors: Vec<String>,
+    #[serde(skip)]
+    timer: Instant,
+}
+
+impl<T: Serialize> JobReport<T> {
+    pub fn new(job: &Job) -> Self {
+        Self {
+            repository: job.repo.full_name(),
+            pull_request: job.pull_request,
+            base_sha: job.base.sha.clone(),
+            head_sha: job.head.sha.clone(),
+            check_run_id: job.check_run.id(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+            finished_at: None,
+            duration_ms: None,
+            files: Vec::new(),
+            errors: Vec::new(),
+            timer: Instant::now(),
+        }
+    }
+
+    pub fn add_file(&mut self, file: T) {
+        self.files.push(file);
+    }
+
+    pub fn add_error<S: Into<String>>(&mut self, error: S) {
+        self.errors.push(error.into());
+    }
+
+    /// Stamps the finish time and serializes the report
+    pub fn finish(&mut self) -> Result<Vec<u8>> {
+        self.finished_at = Some(chrono::Utc::now().to_rfc3339());
+        self.duration_ms = Some(self.timer.elapsed().as_millis());
+        serde_json::to_vec_pretty(self).wrap_err("Serializing job report")
+    }
+}
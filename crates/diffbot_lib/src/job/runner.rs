@@ -1,12 +1,19 @@
-use crate::github::{github_api::CheckRun, github_types::Output};
+use crate::github::{
+    github_api::CheckRun,
+    github_types::{CheckOutputs, Output},
+};
 
 use eyre::Result;
 
 pub async fn handle_output<S: AsRef<str>>(
-    output: Vec<Output>,
+    output: CheckOutputs,
     check_run: &CheckRun,
     name: S,
 ) -> Result<()> {
+    let CheckOutputs {
+        outputs: output,
+        conclusion,
+    } = output;
     match output.len() {
         0 => {
             check_run
@@ -20,7 +27,7 @@ pub async fn handle_output<S: AsRef<str>>(
         }
         1 => {
             check_run
-                .mark_succeeded(output.into_iter().next().unwrap())
+                .mark_completed(output.into_iter().next().unwrap(), conclusion)
                 .await?;
         }
         len => {
@@ -30,13 +37,13 @@ pub async fn handle_output<S: AsRef<str>>(
                         check_run
                             .rename(&format!("{} (1/{len})", name.as_ref()))
                             .await?;
-                        check_run.mark_succeeded(item).await?
+                        check_run.mark_completed(item, conclusion).await?
                     }
                     _ => {
                         let check = check_run
                             .duplicate(&format!("{} ({}/{len})", name.as_ref(), idx + 1))
                             .await?;
-                        check.mark_succeeded(item).await?
+                        check.mark_completed(item, conclusion).await?
                     }
                 };
             }
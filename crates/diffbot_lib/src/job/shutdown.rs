@@ -0,0 +1,120 @@
+use crate::{
+    github::github_types::{Conclusion, Output},
+    job::{tracker, types::Job},
+};
+use eyre::{Context, Result};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Jobs that were still queued or running at shutdown, queued again on the next start
+pub const CHECKPOINT_FILE: &str = "./pending_jobs.json";
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Runners stop taking jobs from the queue once this is set
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+/// Call once the web server stopped, waits up to `grace_period` for running jobs to finish
+///
+/// Whatever is still unfinished afterwards gets its check run marked as neutral and is written to
+/// [`CHECKPOINT_FILE`], see [`restore`].
+pub async fn shutdown(grace_period: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    let deadline = Instant::now() + grace_period;
+    loop {
+        let running = tracker::snapshot().running.len();
+        if running == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("Grace period over with {running} job(s) still running");
+            break;
+        }
+        tracing::info!("Waiting for {running} running job(s) before shutting down");
+        actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    let jobs = tracker::snapshot();
+    let unfinished: Vec<Job> = jobs
+        .running
+        .into_iter()
+        .chain(jobs.queued)
+        .map(|entry| entry.job)
+        .collect();
+    if unfinished.is_empty() {
+        return;
+    }
+
+    for job in unfinished.iter() {
+        if let Err(e) = job
+            .check_run
+            .mark_completed(
+                Output {
+                    title: "Bot restarted",
+                    summary: "The bot restarted before this job finished, it will be retried automatically."
+                        .to_owned(),
+                    text: "".to_owned(),
+                },
+                Conclusion::Neutral,
+            )
+            .await
+        {
+            tracing::error!(
+                "Failed to mark check run {} as interrupted: {e:?}",
+                job.check_run.id()
+            );
+        }
+    }
+
+    match write_checkpoint(&unfinished).await {
+        Ok(()) => tracing::info!(
+            "Saved {} unfinished job(s) to {CHECKPOINT_FILE}",
+            unfinished.len()
+        ),
+        Err(e) => tracing::error!("{e:?}"),
+    }
+}
+
+async fn write_checkpoint(jobs: &[Job]) -> Result<()> {
+    let json = serde_json::to_vec_pretty(jobs).wrap_err("Serializing unfinished jobs")?;
+    async_fs::write(CHECKPOINT_FILE, json)
+        .await
+        .wrap_err_with(|| format!("Writing {CHECKPOINT_FILE}"))
+}
+
+/// Takes the jobs saved by the last [`shutdown`], send them to the queue
+///
+/// Their check runs were completed at shutdown, so each job gets a fresh one.
+pub async fn restore() -> Result<Vec<Job>> {
+    let json = match async_fs::read(CHECKPOINT_FILE).await {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Reading {CHECKPOINT_FILE}")),
+    };
+    let jobs: Vec<Job> =
+        serde_json::from_slice(&json).wrap_err_with(|| format!("Parsing {CHECKPOINT_FILE}"))?;
+    async_fs::remove_file(CHECKPOINT_FILE)
+        .await
+        .wrap_err_with(|| format!("Removing {CHECKPOINT_FILE}"))?;
+
+    let mut restored = Vec::with_capacity(jobs.len());
+    for mut job in jobs {
+        // The result still gets posted to the old run if this fails
+        match job.check_run.recreate().await {
+            Ok(check_run) => job.check_run = check_run,
+            Err(e) => tracing::error!(
+                "Failed to create a new check run for restored job {}: {e:?}",
+                job.check_run.id()
+            ),
+        }
+        tracker::queued(&job);
+        restored.push(job);
+    }
+    tracing::info!("Restored {} job(s) from {CHECKPOINT_FILE}", restored.len());
+    Ok(restored)
+}
@@ -0,0 +1,442 @@
+use crate::{job::types::Job, metrics};
+use chrono::{DateTime, Utc};
+use prometheus::HistogramTimer;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+/// How many finished jobs are kept around for the admin page
+const MAX_RECENT: usize = 50;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "outcome", content = "error")]
+pub enum JobResult {
+    Succeeded,
+    Failed(String),
+    TimedOut,
+    Cancelled,
+}
+
+impl JobResult {
+    pub fn outcome(&self) -> &'static str {
+        match self {
+            JobResult::Succeeded => "succeeded",
+            JobResult::Failed(_) => "failed",
+            JobResult::TimedOut => "timed_out",
+            JobResult::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Set once a job is cancelled or timed out, `do_job` checks it between stages to stop early
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Errors out if the job was cancelled, the result isn't wanted anymore at that point
+    pub fn check(&self) -> eyre::Result<()> {
+        if self.is_cancelled() {
+            return Err(eyre::eyre!("Job was cancelled"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JobEntry {
+    pub check_run_id: u64,
+    pub repo: String,
+    pub pull_request: u64,
+    pub head_sha: String,
+    pub state: JobState,
+    pub stage: Option<String>,
+    /// How far the job got in its current stage
+    pub progress: Option<String>,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub result: Option<JobResult>,
+    #[serde(skip)]
+    pub job: Job,
+    #[serde(skip)]
+    cancel: Option<flume::Sender<()>>,
+    #[serde(skip)]
+    token: CancelToken,
+}
+
+impl JobEntry {
+    /// Seconds since the job started, or how long it ran for if it finished
+    pub fn elapsed_secs(&self) -> Option<i64> {
+        let started = self.started_at?;
+        let until = self.finished_at.unwrap_or_else(Utc::now);
+        Some((until - started).num_seconds())
+    }
+}
+
+/// Everything the bot knows about its jobs, see [`snapshot`]
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct JobList {
+    pub queued: Vec<JobEntry>,
+    pub running: Vec<JobEntry>,
+    pub recent: Vec<JobEntry>,
+}
+
+#[derive(Default)]
+struct Tracker {
+    active: Vec<JobEntry>,
+    recent: VecDeque<JobEntry>,
+}
+
+impl Tracker {
+    fn active_mut(&mut self, check_run_id: u64) -> Option<&mut JobEntry> {
+        self.active
+            .iter_mut()
+            .find(|entry| entry.check_run_id == check_run_id)
+    }
+
+    fn finish(&mut self, check_run_id: u64, result: JobResult) {
+        let Some(position) = self
+            .active
+            .iter()
+            .position(|entry| entry.check_run_id == check_run_id)
+        else {
+            return;
+        };
+        let mut entry = self.active.remove(position);
+        metrics::job_finished(result.outcome());
+        entry.state = JobState::Finished;
+        entry.finished_at = Some(Utc::now());
+        entry.result = Some(result);
+        entry.cancel = None;
+        self.recent.retain(|old| old.check_run_id != check_run_id);
+        self.recent.push_front(entry);
+        self.recent.truncate(MAX_RECENT);
+    }
+}
+
+static TRACKER: LazyLock<Mutex<Tracker>> = LazyLock::new(Default::default);
+
+fn tracker() -> MutexGuard<'static, Tracker> {
+    TRACKER
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Call right before sending the job to the queue
+pub fn queued(job: &Job) {
+    let mut tracker = tracker();
+    let check_run_id = job.check_run.id();
+    tracker
+        .recent
+        .retain(|entry| entry.check_run_id != check_run_id);
+    tracker.active.push(JobEntry {
+        check_run_id,
+        repo: job.repo.full_name(),
+        pull_request: job.pull_request,
+        head_sha: job.head.sha.clone(),
+        state: JobState::Queued,
+        stage: None,
+        progress: None,
+        queued_at: Utc::now(),
+        started_at: None,
+        finished_at: None,
+        result: None,
+        job: job.clone(),
+        cancel: None,
+        token: CancelToken::default(),
+    });
+}
+
+/// Queues a job again under its new check run, the finished entry of the old run goes away
+pub fn requeued(old_check_run_id: u64, job: &Job) {
+    tracker()
+        .recent
+        .retain(|entry| entry.check_run_id != old_check_run_id);
+    queued(job);
+}
+
+/// Marks the job as running, `None` if it was cancelled while it was queued
+///
+/// The receiver gets a message once the job is cancelled, see [`until_cancelled`]. The token is
+/// for the job itself.
+pub fn started(job: &Job) -> Option<(flume::Receiver<()>, CancelToken)> {
+    let mut tracker = tracker();
+    let check_run_id = job.check_run.id();
+    if tracker.active_mut(check_run_id).is_none() {
+        if tracker.recent.iter().any(|entry| {
+            entry.check_run_id == check_run_id && entry.result == Some(JobResult::Cancelled)
+        }) {
+            return None;
+        }
+        // Queued before the tracker knew about it
+        drop(tracker);
+        queued(job);
+        tracker = self::tracker();
+    }
+
+    metrics::job_started();
+    let (sender, receiver) = flume::bounded(1);
+    let entry = tracker
+        .active_mut(check_run_id)
+        .expect("Job was just added");
+    entry.state = JobState::Running;
+    entry.started_at = Some(Utc::now());
+    entry.cancel = Some(sender);
+    Some((receiver, entry.token.clone()))
+}
+
+/// Shows the stage on the admin page and times it until the returned timer is dropped
+pub fn enter_stage(check_run_id: u64, stage: &str) -> HistogramTimer {
+    if let Some(entry) = tracker().active_mut(check_run_id) {
+        entry.stage = Some(stage.to_owned());
+        entry.progress = None;
+    }
+    metrics::time_stage(stage)
+}
+
+/// Shows how far the job got in its current stage, e.g. `3 of 10 maps rendered`
+pub fn progress(check_run_id: u64, progress: String) {
+    if let Some(entry) = tracker().active_mut(check_run_id) {
+        entry.progress = Some(progress);
+    }
+}
+
+/// Stops the job and returns a message saying where it was when it ran out of time
+pub fn timed_out(check_run_id: u64, timeout: Duration) -> String {
+    let mut tracker = tracker();
+    let mut message = format!("Job timed out after {}", format_duration(timeout));
+    if let Some(entry) = tracker.active_mut(check_run_id) {
+        entry.token.cancel();
+        if let Some(ref stage) = entry.stage {
+            message.push_str(&format!(" in the `{stage}` stage"));
+        }
+        if let Some(ref progress) = entry.progress {
+            message.push_str(&format!(", {progress}"));
+        }
+    }
+    tracker.finish(check_run_id, JobResult::TimedOut);
+    message.push('!');
+    message
+}
+
+fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs if secs == 0 || secs % 60 != 0 => format!("{secs} seconds"),
+        secs if secs % 3600 != 0 => format!("{} minutes", secs / 60),
+        secs => format!("{} hours", secs / 3600),
+    }
+}
+
+pub fn finished(check_run_id: u64, result: JobResult) {
+    tracker().finish(check_run_id, result);
+}
+
+/// Cancels a queued or running job, returns the job if it was found
+///
+/// Queued jobs are skipped once they reach the front of the queue, running ones are abandoned.
+pub fn cancel(check_run_id: u64) -> Option<Job> {
+    let mut tracker = tracker();
+    let entry = tracker.active_mut(check_run_id)?;
+    let job = entry.job.clone();
+    entry.token.cancel();
+    if let Some(ref cancel) = entry.cancel {
+        _ = cancel.try_send(());
+    }
+    tracker.finish(check_run_id, JobResult::Cancelled);
+    Some(job)
+}
+
+/// The job of a finished run that didn't succeed, so it can be queued again
+pub fn retryable(check_run_id: u64) -> Option<Job> {
+    tracker()
+        .recent
+        .iter()
+        .find(|entry| {
+            entry.check_run_id == check_run_id
+                && entry
+                    .result
+                    .as_ref()
+                    .is_some_and(|result| *result != JobResult::Succeeded)
+        })
+        .map(|entry| entry.job.clone())
+}
+
+pub fn snapshot() -> JobList {
+    let tracker = tracker();
+    let (running, queued) = tracker
+        .active
+        .iter()
+        .cloned()
+        .partition(|entry| entry.state == JobState::Running);
+    JobList {
+        queued,
+        running,
+        recent: tracker.recent.iter().cloned().collect(),
+    }
+}
+
+/// Runs the future until it completes or the job gets cancelled, `None` if it was cancelled
+pub async fn until_cancelled<F: Future>(
+    cancel: flume::Receiver<()>,
+    future: F,
+) -> Option<F::Output> {
+    use futures::future::{select, Either};
+    match select(std::pin::pin!(future), cancel.recv_async()).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right((Ok(()), _)) => None,
+        // The sender only goes away once the job finished, keep waiting for the result
+        Either::Right((Err(_), future)) => Some(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tracker is shared by every test, so each one uses its own check run ids
+    fn job(check_run_id: u64) -> Job {
+        serde_json::from_value(serde_json::json!({
+            "repo": { "url": "https://api.github.com/repos/owner/repo", "id": 1 },
+            "base": { "sha": "base", "ref": "master" },
+            "head": { "sha": "head", "ref": "feature" },
+            "pull_request": 1,
+            "files": [],
+            "check_run": {
+                "id": check_run_id,
+                "installation_id": 1,
+                "head_sha": "head",
+                "repo": "owner/repo"
+            },
+            "installation": 1
+        }))
+        .unwrap()
+    }
+
+    fn entry(list: &[JobEntry], check_run_id: u64) -> Option<&JobEntry> {
+        list.iter().find(|entry| entry.check_run_id == check_run_id)
+    }
+
+    #[test]
+    fn job_moves_from_queued_to_running_to_recent() {
+        let job = job(101);
+        queued(&job);
+        assert!(entry(&snapshot().queued, 101).is_some());
+
+        assert!(started(&job).is_some());
+        let jobs = snapshot();
+        assert!(entry(&jobs.queued, 101).is_none());
+        assert_eq!(entry(&jobs.running, 101).unwrap().state, JobState::Running);
+
+        finished(101, JobResult::Succeeded);
+        let jobs = snapshot();
+        assert!(entry(&jobs.running, 101).is_none());
+        let recent = entry(&jobs.recent, 101).unwrap();
+        assert_eq!(recent.state, JobState::Finished);
+        assert_eq!(recent.result, Some(JobResult::Succeeded));
+    }
+
+    #[test]
+    fn job_started_without_being_queued_is_tracked() {
+        assert!(started(&job(102)).is_some());
+        assert!(entry(&snapshot().running, 102).is_some());
+        finished(102, JobResult::Succeeded);
+    }
+
+    #[test]
+    fn job_cancelled_while_queued_does_not_start() {
+        let job = job(103);
+        queued(&job);
+        assert!(cancel(103).is_some());
+        assert!(started(&job).is_none());
+        assert_eq!(
+            entry(&snapshot().recent, 103).unwrap().result,
+            Some(JobResult::Cancelled)
+        );
+    }
+
+    #[test]
+    fn cancelling_running_job_sets_its_token() {
+        let job = job(104);
+        let (receiver, token) = started(&job).unwrap();
+        assert!(!token.is_cancelled());
+        assert!(cancel(104).is_some());
+        assert!(token.is_cancelled());
+        assert!(token.check().is_err());
+        assert!(receiver.try_recv().is_ok());
+        assert!(cancel(104).is_none());
+    }
+
+    #[test]
+    fn only_unsuccessful_jobs_are_retryable() {
+        started(&job(105));
+        finished(105, JobResult::Succeeded);
+        assert!(retryable(105).is_none());
+
+        started(&job(106));
+        finished(106, JobResult::Failed("error".to_owned()));
+        assert_eq!(retryable(106).unwrap().check_run.id(), 106);
+
+        started(&job(107));
+        finished(107, JobResult::TimedOut);
+        assert!(retryable(107).is_some());
+
+        // Still running
+        started(&job(108));
+        assert!(retryable(108).is_none());
+        finished(108, JobResult::Succeeded);
+    }
+
+    #[test]
+    fn requeued_job_replaces_the_old_run() {
+        started(&job(109));
+        finished(109, JobResult::Failed("error".to_owned()));
+
+        requeued(109, &job(110));
+        let jobs = snapshot();
+        assert!(entry(&jobs.recent, 109).is_none());
+        assert!(retryable(109).is_none());
+        assert_eq!(entry(&jobs.queued, 110).unwrap().state, JobState::Queued);
+    }
+
+    #[test]
+    fn timed_out_message_names_the_stage() {
+        let job = job(111);
+        let (_, token) = started(&job).unwrap();
+        drop(enter_stage(111, "render"));
+        progress(111, "3 of 10 maps rendered".to_owned());
+
+        let message = timed_out(111, Duration::from_secs(120));
+        assert_eq!(
+            message,
+            "Job timed out after 2 minutes in the `render` stage, 3 of 10 maps rendered!"
+        );
+        assert!(token.is_cancelled());
+        assert_eq!(
+            entry(&snapshot().recent, 111).unwrap().result,
+            Some(JobResult::TimedOut)
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::github::{
     github_api::CheckRun,
-    github_types::{self, Branch, CheckOutputs, FileDiff},
+    github_types::{self, Branch, CheckOutputs, FileDiff, InstallationRepository},
 };
 use eyre::Result;
 use flume::Sender;
@@ -15,6 +15,12 @@ pub type JobSender<T> = Sender<T>;
 pub enum JobType {
     GithubJob(Box<Job>),
     CleanupJob,
+    /// Clones a newly installed repository before its first PR needs it
+    CloneJob(InstallationId, InstallationRepository),
+    /// Deletes everything stored for a repository the app lost access to
+    RemoveRepositoryJob(InstallationId, InstallationRepository),
+    /// Deletes whatever is left of an uninstalled account, by login
+    RemoveOwnerJob(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -26,4 +32,20 @@ pub struct Job {
     pub files: Vec<FileDiff>,
     pub check_run: CheckRun,
     pub installation: InstallationId,
+    #[serde(default)]
+    pub options: JobOptions,
+    /// What was left out of the job because of resource limits, shown in the check summary
+    #[serde(default)]
+    pub truncated: Vec<String>,
+}
+
+/// Rendering overrides requested through PR labels or comment commands
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobOptions {
+    /// Render everything instead of only what changed
+    pub full_render: bool,
+    /// Also render z-levels without any changes
+    pub all_z_levels: bool,
+    /// Only render this z-level, 1-indexed
+    pub z_level: Option<usize>,
 }
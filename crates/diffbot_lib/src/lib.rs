@@ -1,6 +1,11 @@
+pub mod admin;
+pub mod db;
 pub mod github;
 pub mod job;
 pub mod logger;
+pub mod metrics;
+pub mod policy;
+pub mod repo_config;
 pub mod verify;
 pub use async_fs;
 pub use tracing;
@@ -0,0 +1,146 @@
+use octocrab::models::InstallationId;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::{LazyLock, OnceLock};
+
+/// Everything exposed on `/metrics`, see [`metrics`]
+pub struct Metrics {
+    registry: Registry,
+    /// Jobs waiting to be picked up
+    pub queue_depth: IntGauge,
+    /// Jobs by outcome: started, succeeded, failed or timed_out
+    pub jobs: IntCounterVec,
+    /// Seconds spent in each stage of a job
+    pub stage_duration: HistogramVec,
+    pub images_written: IntCounter,
+    pub bytes_stored: IntCounter,
+    /// GitHub API requests by operation
+    pub github_api_calls: IntCounterVec,
+    /// Remaining core rate limit by installation, as of the last finished job
+    pub github_rate_limit_remaining: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("diffbot".to_owned()), None)?;
+
+        let queue_depth = IntGauge::new("queue_depth", "Jobs waiting in the queue")?;
+        let jobs = IntCounterVec::new(Opts::new("jobs_total", "Jobs by outcome"), &["outcome"])?;
+        let stage_duration = HistogramVec::new(
+            HistogramOpts::new("stage_duration_seconds", "Time spent in each job stage")
+                .buckets(vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0]),
+            &["stage"],
+        )?;
+        let images_written = IntCounter::new("images_written_total", "Rendered images stored")?;
+        let bytes_stored =
+            IntCounter::new("bytes_stored_total", "Bytes of rendered images stored")?;
+        let github_api_calls = IntCounterVec::new(
+            Opts::new("github_api_calls_total", "GitHub API requests by operation"),
+            &["operation"],
+        )?;
+        let github_rate_limit_remaining = IntGaugeVec::new(
+            Opts::new(
+                "github_rate_limit_remaining",
+                "Remaining GitHub API requests by installation",
+            ),
+            &["installation"],
+        )?;
+
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(jobs.clone()))?;
+        registry.register(Box::new(stage_duration.clone()))?;
+        registry.register(Box::new(images_written.clone()))?;
+        registry.register(Box::new(bytes_stored.clone()))?;
+        registry.register(Box::new(github_api_calls.clone()))?;
+        registry.register(Box::new(github_rate_limit_remaining.clone()))?;
+
+        Ok(Self {
+            registry,
+            queue_depth,
+            jobs,
+            stage_duration,
+            images_written,
+            bytes_stored,
+            github_api_calls,
+            github_rate_limit_remaining,
+        })
+    }
+}
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("Failed to register metrics"));
+
+type QueueLength = Box<dyn Fn() -> usize + Send + Sync>;
+static QUEUE_LENGTH: OnceLock<QueueLength> = OnceLock::new();
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Reports the length of this queue as the queue depth, read whenever metrics are scraped
+pub fn observe_queue<T: Send + 'static>(receiver: flume::Receiver<T>) {
+    _ = QUEUE_LENGTH.set(Box::new(move || receiver.len()));
+}
+
+pub fn job_started() {
+    METRICS.jobs.with_label_values(&["started"]).inc();
+}
+
+/// `outcome` is one of succeeded, failed or timed_out
+pub fn job_finished(outcome: &str) {
+    METRICS.jobs.with_label_values(&[outcome]).inc();
+}
+
+/// Records the time until the returned timer is dropped as the duration of `stage`
+pub fn time_stage(stage: &str) -> HistogramTimer {
+    METRICS
+        .stage_duration
+        .with_label_values(&[stage])
+        .start_timer()
+}
+
+pub fn image_stored(bytes: usize) {
+    METRICS.images_written.inc();
+    METRICS.bytes_stored.inc_by(bytes as u64);
+}
+
+pub fn github_api_call(operation: &str) {
+    METRICS
+        .github_api_calls
+        .with_label_values(&[operation])
+        .inc();
+}
+
+/// Asks GitHub for the installation's remaining rate limit, which doesn't count against it
+pub async fn update_rate_limit(installation: InstallationId) {
+    let rate_limit = match octocrab::instance().installation(installation) {
+        Ok(crab) => crab.ratelimit().get().await,
+        Err(e) => Err(e),
+    };
+    match rate_limit {
+        Ok(rate_limit) => METRICS
+            .github_rate_limit_remaining
+            .with_label_values(&[&installation.to_string()])
+            .set(rate_limit.rate.remaining as i64),
+        Err(e) => tracing::warn!("Failed to get rate limit for installation {installation}: {e}"),
+    }
+}
+
+#[actix_web::get("/metrics")]
+pub async fn metrics_endpoint() -> actix_web::HttpResponse {
+    if let Some(queue_length) = QUEUE_LENGTH.get() {
+        METRICS.queue_depth.set(queue_length() as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&METRICS.registry.gather(), &mut body) {
+        tracing::error!("Failed to encode metrics: {e}");
+        return actix_web::HttpResponse::InternalServerError().finish();
+    }
+    actix_web::HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
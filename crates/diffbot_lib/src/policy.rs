@@ -0,0 +1,300 @@
+use crate::github::github_types::{Output, Repository};
+use eyre::{Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Who may use the bot and per installation overrides, the `[policy]` section of the server config
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Only the installations and owners listed below may use the bot
+    pub allowlist: bool,
+    pub allowed_installations: HashSet<u64>,
+    /// Logins of users or organizations, compared case insensitively
+    pub allowed_owners: Vec<String>,
+    pub blocked_installations: HashSet<u64>,
+    pub blocked_owners: Vec<String>,
+    /// Days rendered images are kept after the PR closes, unlimited if unset
+    pub retention_days: Option<u32>,
+    /// Overrides for specific installations, keyed by installation id
+    pub installations: HashMap<String, InstallationPolicy>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstallationPolicy {
+    /// Overrides for keys of the `[limits]` section
+    pub limits: toml::Table,
+    /// Azure container the images are stored in
+    pub storage_container: Option<String>,
+    pub retention_days: Option<u32>,
+}
+
+/// Why a repository may not use the bot, shown on its skipped check run
+pub struct Denied {
+    pub title: &'static str,
+    pub summary: String,
+}
+
+impl Denied {
+    pub fn into_output(self) -> Output {
+        Output {
+            title: self.title,
+            summary: self.summary,
+            text: "".to_owned(),
+        }
+    }
+}
+
+impl PolicyConfig {
+    fn installation(&self, installation: u64) -> Option<&InstallationPolicy> {
+        self.installations.get(&installation.to_string())
+    }
+
+    /// Checks the installation, the repository's owner and the repository blacklist
+    pub fn check(
+        &self,
+        installation: u64,
+        repository: &Repository,
+        (blacklist, contact): (&HashSet<u64>, &str),
+    ) -> Result<(), Denied> {
+        let full_name = repository.full_name();
+        let owner = full_name.split('/').next().unwrap_or_default();
+        let listed = |owners: &[String]| owners.iter().any(|o| o.eq_ignore_ascii_case(owner));
+
+        if blacklist.contains(&repository.id) {
+            return Err(Denied {
+                title: "Repo blacklisted",
+                summary: format!("Repository {full_name} is blacklisted. {contact}"),
+            });
+        }
+
+        let blocked = self.blocked_installations.contains(&installation)
+            || listed(&self.blocked_owners)
+            || (self.allowlist
+                && !self.allowed_installations.contains(&installation)
+                && !listed(&self.allowed_owners));
+        if blocked {
+            return Err(Denied {
+                title: "Not allowed",
+                summary: format!("{owner} is not allowed to use this bot. {contact}"),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The server's limits with the installation's overrides applied
+    pub fn limits<L: Clone + Serialize + DeserializeOwned>(
+        &self,
+        installation: u64,
+        limits: &L,
+    ) -> Result<L> {
+        let Some(overrides) = self
+            .installation(installation)
+            .map(|policy| &policy.limits)
+            .filter(|overrides| !overrides.is_empty())
+        else {
+            return Ok(limits.clone());
+        };
+
+        let mut value = toml::Value::try_from(limits).wrap_err("Serializing limits")?;
+        if let toml::Value::Table(ref mut table) = value {
+            table.extend(overrides.clone());
+        }
+        value
+            .try_into()
+            .wrap_err_with(|| format!("Parsing limits of installation {installation}"))
+    }
+
+    pub fn storage_container(&self, installation: u64) -> Option<&str> {
+        self.installation(installation)?
+            .storage_container
+            .as_deref()
+    }
+
+    pub fn retention_days(&self, installation: u64) -> Option<u32> {
+        self.installation(installation)
+            .and_then(|policy| policy.retention_days)
+            .or(self.retention_days)
+    }
+
+    /// Makes sure every installation's overrides apply, call once after loading the config
+    pub fn validate<L: Clone + Serialize + DeserializeOwned>(&self, limits: &L) -> Result<()> {
+        for id in self.installations.keys() {
+            let installation: u64 = id
+                .parse()
+                .wrap_err_with(|| format!("Policy key {id:?} is not an installation id"))?;
+            self.limits(installation, limits)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct Limits {
+        max_files: usize,
+        max_output_bytes: u64,
+    }
+
+    impl Default for Limits {
+        fn default() -> Self {
+            Self {
+                max_files: 50,
+                max_output_bytes: 1024,
+            }
+        }
+    }
+
+    fn policy(toml: &str) -> PolicyConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn repository(full_name: &str) -> Repository {
+        Repository {
+            url: format!("https://api.github.com/repos/{full_name}"),
+            id: 1,
+        }
+    }
+
+    fn allowed(policy: &PolicyConfig, installation: u64, full_name: &str) -> bool {
+        policy
+            .check(installation, &repository(full_name), (&HashSet::new(), ""))
+            .is_ok()
+    }
+
+    #[test]
+    fn everyone_is_allowed_by_default() {
+        assert!(allowed(&policy(""), 1, "owner/repo"));
+    }
+
+    #[test]
+    fn blacklisted_repository_is_denied() {
+        let denied = policy("")
+            .check(
+                1,
+                &repository("owner/repo"),
+                (&HashSet::from([1]), "Ask us."),
+            )
+            .unwrap_err();
+        assert_eq!(denied.title, "Repo blacklisted");
+        assert!(denied.summary.ends_with("Ask us."));
+    }
+
+    #[test]
+    fn blocklist_denies_installation_and_owner() {
+        let policy = policy(
+            r#"
+blocked_installations = [2]
+blocked_owners = ["Blocked"]
+"#,
+        );
+        assert!(allowed(&policy, 1, "owner/repo"));
+        assert!(!allowed(&policy, 2, "owner/repo"));
+        assert!(!allowed(&policy, 1, "blocked/repo"));
+    }
+
+    #[test]
+    fn allowlist_only_allows_listed_installations_and_owners() {
+        let policy = policy(
+            r#"
+allowlist = true
+allowed_installations = [1]
+allowed_owners = ["Friend"]
+"#,
+        );
+        assert!(allowed(&policy, 1, "owner/repo"));
+        assert!(allowed(&policy, 2, "friend/repo"));
+        assert!(!allowed(&policy, 2, "owner/repo"));
+    }
+
+    #[test]
+    fn blocklist_wins_over_allowlist() {
+        let policy = policy(
+            r#"
+allowlist = true
+allowed_installations = [1]
+blocked_owners = ["owner"]
+"#,
+        );
+        assert!(!allowed(&policy, 1, "owner/repo"));
+    }
+
+    #[test]
+    fn allowlist_is_ignored_unless_enabled() {
+        let policy = policy("allowed_installations = [1]");
+        assert!(allowed(&policy, 2, "owner/repo"));
+    }
+
+    #[test]
+    fn installation_overrides_replace_only_their_keys() {
+        let policy = policy(
+            r#"
+[installations.1.limits]
+max_files = 10
+"#,
+        );
+        let limits = Limits::default();
+        assert_eq!(
+            policy.limits(1, &limits).unwrap(),
+            Limits {
+                max_files: 10,
+                ..limits
+            }
+        );
+        assert_eq!(policy.limits(2, &limits).unwrap(), limits);
+        assert!(policy.validate(&limits).is_ok());
+    }
+
+    #[test]
+    fn mistyped_override_fails_validation() {
+        let policy = policy(
+            r#"
+[installations.1.limits]
+max_file = 10
+"#,
+        );
+        assert!(policy.validate(&Limits::default()).is_err());
+    }
+
+    #[test]
+    fn override_of_the_wrong_type_fails_validation() {
+        let policy = policy(
+            r#"
+[installations.1.limits]
+max_files = "ten"
+"#,
+        );
+        assert!(policy.validate(&Limits::default()).is_err());
+    }
+
+    #[test]
+    fn installation_keys_must_be_ids() {
+        let policy = policy(
+            r#"
+[installations.owner.limits]
+max_files = 10
+"#,
+        );
+        assert!(policy.validate(&Limits::default()).is_err());
+    }
+
+    #[test]
+    fn retention_falls_back_to_the_server_default() {
+        let policy = policy(
+            r#"
+retention_days = 30
+
+[installations.1]
+retention_days = 7
+"#,
+        );
+        assert_eq!(policy.retention_days(1), Some(7));
+        assert_eq!(policy.retention_days(2), Some(30));
+    }
+}
@@ -0,0 +1,279 @@
+use eyre::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Top level key listing the settings a PR is allowed to change for its own run
+pub const PR_OVERRIDABLE_KEY: &str = "pr_overridable";
+
+/// A repository config read from the base commit, with the overrides the base allows taken from the head commit
+#[derive(Debug, Default)]
+pub struct MergedConfig {
+    pub table: toml::Table,
+    /// Whether the PR changes the config file at all
+    pub modified_by_pr: bool,
+    /// Keys that were taken from the PR's version of the config
+    pub overridden: Vec<String>,
+    /// Set if the PR's version of the config couldn't be parsed, no overrides are applied then
+    pub head_error: Option<String>,
+}
+
+pub fn merge_configs(base: Option<&str>, head: Option<&str>) -> Result<MergedConfig> {
+    let mut table: toml::Table = match base {
+        Some(base) => toml::from_str(base).wrap_err("Parsing the base branch's config")?,
+        None => Default::default(),
+    };
+
+    let modified_by_pr = base != head;
+    if !modified_by_pr {
+        return Ok(MergedConfig {
+            table,
+            ..Default::default()
+        });
+    }
+
+    let allowed: Vec<String> = match table.get(PR_OVERRIDABLE_KEY) {
+        Some(value) => value
+            .clone()
+            .try_into()
+            .wrap_err_with(|| format!("`{PR_OVERRIDABLE_KEY}` has to be a list of keys"))?,
+        None => Vec::new(),
+    };
+
+    let mut overridden = Vec::new();
+    let mut head_error = None;
+
+    if !allowed.is_empty() {
+        let head_table: Result<toml::Table> = match head {
+            Some(head) => toml::from_str(head).wrap_err("Parsing the PR's config"),
+            None => Ok(Default::default()),
+        };
+
+        match head_table {
+            Ok(head_table) => {
+                for key in allowed.iter().filter(|key| *key != PR_OVERRIDABLE_KEY) {
+                    let head_value = head_table.get(key);
+                    if table.get(key) == head_value {
+                        continue;
+                    }
+                    match head_value {
+                        Some(value) => table.insert(key.clone(), value.clone()),
+                        None => table.remove(key),
+                    };
+                    overridden.push(key.clone());
+                }
+            }
+            Err(e) => head_error = Some(format!("{e:?}")),
+        }
+    }
+
+    Ok(MergedConfig {
+        table,
+        modified_by_pr,
+        overridden,
+        head_error,
+    })
+}
+
+impl MergedConfig {
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        toml::Value::Table(self.table.clone())
+            .try_into()
+            .wrap_err("Deserializing config")
+    }
+
+    /// Text to show in the check summary if the PR touches the config
+    pub fn notice(&self, file_name: &str) -> Option<String> {
+        if !self.modified_by_pr {
+            return None;
+        }
+        let mut notice = format!(
+            "**This PR modifies `{file_name}`.** Settings are read from the base branch, so most changes will only apply once it is merged."
+        );
+        if !self.overridden.is_empty() {
+            notice.push_str(&format!(
+                " The following settings were taken from this PR: {}.",
+                self.overridden
+                    .iter()
+                    .map(|key| format!("`{key}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        if let Some(ref error) = self.head_error {
+            notice.push_str(&format!(
+                "\n\nThe PR's version of `{file_name}` could not be parsed:\n```\n{error}\n```"
+            ));
+        }
+        Some(notice)
+    }
+}
+
+/// Matches paths against the `include` and `exclude` globs of a repository config
+#[derive(Debug)]
+pub struct PathFilter {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        Ok(Self {
+            include: build_globset(include)?,
+            exclude: build_globset(exclude)?,
+        })
+    }
+
+    /// Everything is included without any include globs, excludes win over includes
+    pub fn is_match(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+fn build_globset(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).wrap_err_with(|| format!("Invalid glob {glob:?}"))?);
+    }
+    builder.build().wrap_err("Building globset")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = r#"
+pr_overridable = ["upscale", "include"]
+upscale = 2
+include = ["icons/**"]
+show_unchanged = false
+"#;
+
+    fn globs(globs: &[&str]) -> Vec<String> {
+        globs.iter().map(|glob| glob.to_string()).collect()
+    }
+
+    #[test]
+    fn unmodified_config_is_used_as_is() {
+        let merged = merge_configs(Some(BASE), Some(BASE)).unwrap();
+        assert_eq!(merged.table, toml::from_str::<toml::Table>(BASE).unwrap());
+        assert!(!merged.modified_by_pr);
+        assert!(merged.notice("config.toml").is_none());
+    }
+
+    #[test]
+    fn missing_config_is_empty() {
+        let merged = merge_configs(None, None).unwrap();
+        assert!(merged.table.is_empty());
+        assert!(!merged.modified_by_pr);
+    }
+
+    #[test]
+    fn invalid_base_config_is_an_error() {
+        assert!(merge_configs(Some("upscale = "), Some(BASE)).is_err());
+    }
+
+    #[test]
+    fn overridable_keys_are_taken_from_the_pr() {
+        let head = BASE.replace("upscale = 2", "upscale = 4");
+        let merged = merge_configs(Some(BASE), Some(&head)).unwrap();
+        assert_eq!(merged.table["upscale"].as_integer(), Some(4));
+        assert_eq!(merged.overridden, ["upscale"]);
+        assert!(merged.notice("config.toml").unwrap().contains("`upscale`"));
+    }
+
+    #[test]
+    fn other_keys_stay_on_the_base_value() {
+        let head = BASE.replace("show_unchanged = false", "show_unchanged = true");
+        let merged = merge_configs(Some(BASE), Some(&head)).unwrap();
+        assert_eq!(merged.table["show_unchanged"].as_bool(), Some(false));
+        assert!(merged.modified_by_pr);
+        assert!(merged.overridden.is_empty());
+        assert!(merged.notice("config.toml").is_some());
+    }
+
+    #[test]
+    fn pr_cannot_change_what_is_overridable() {
+        let head = BASE
+            .replace(
+                r#"pr_overridable = ["upscale", "include"]"#,
+                r#"pr_overridable = ["upscale", "include", "show_unchanged"]"#,
+            )
+            .replace("show_unchanged = false", "show_unchanged = true");
+        let merged = merge_configs(Some(BASE), Some(&head)).unwrap();
+        assert_eq!(
+            merged.table[PR_OVERRIDABLE_KEY],
+            toml::from_str::<toml::Table>(BASE).unwrap()[PR_OVERRIDABLE_KEY]
+        );
+        assert_eq!(merged.table["show_unchanged"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn removing_an_overridable_key_removes_it() {
+        let head = BASE.replace(r#"include = ["icons/**"]"#, "");
+        let merged = merge_configs(Some(BASE), Some(&head)).unwrap();
+        assert!(!merged.table.contains_key("include"));
+        assert_eq!(merged.overridden, ["include"]);
+    }
+
+    #[test]
+    fn deleting_the_config_in_the_pr_removes_overridable_keys() {
+        let merged = merge_configs(Some(BASE), None).unwrap();
+        assert!(!merged.table.contains_key("upscale"));
+        assert!(!merged.table.contains_key("include"));
+        assert_eq!(merged.table["show_unchanged"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn nothing_is_overridden_without_overridable_keys() {
+        let merged = merge_configs(Some("upscale = 2"), Some("upscale = 4")).unwrap();
+        assert_eq!(merged.table["upscale"].as_integer(), Some(2));
+        assert!(merged.overridden.is_empty());
+    }
+
+    #[test]
+    fn invalid_pr_config_keeps_the_base() {
+        let merged = merge_configs(Some(BASE), Some("upscale = ")).unwrap();
+        assert_eq!(merged.table, toml::from_str::<toml::Table>(BASE).unwrap());
+        assert!(merged.overridden.is_empty());
+        assert!(merged.head_error.is_some());
+        assert!(merged
+            .notice("config.toml")
+            .unwrap()
+            .contains("could not be parsed"));
+    }
+
+    #[test]
+    fn invalid_overridable_list_is_an_error() {
+        assert!(merge_configs(Some("pr_overridable = 1"), Some("")).is_err());
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = PathFilter::new(&[], &[]).unwrap();
+        assert!(filter.is_match("icons/obj/doors.dmi"));
+    }
+
+    #[test]
+    fn include_limits_the_matches() {
+        let filter = PathFilter::new(&globs(&["icons/obj/**"]), &[]).unwrap();
+        assert!(filter.is_match("icons/obj/doors.dmi"));
+        assert!(!filter.is_match("icons/mob/human.dmi"));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = PathFilter::new(
+            &globs(&["_maps/**"]),
+            &globs(&["_maps/templates/**", "**/*.backup.dmm"]),
+        )
+        .unwrap();
+        assert!(filter.is_match("_maps/station/box.dmm"));
+        assert!(!filter.is_match("_maps/templates/ruin.dmm"));
+        assert!(!filter.is_match("_maps/station/box.backup.dmm"));
+    }
+
+    #[test]
+    fn invalid_glob_is_an_error() {
+        assert!(PathFilter::new(&globs(&["icons/[oops"]), &[]).is_err());
+        assert!(PathFilter::new(&[], &globs(&["{a,b"])).is_err());
+    }
+}
@@ -6,30 +6,201 @@ use sha2::Sha256;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// Length of a SHA-256 HMAC in bytes
+const SIGNATURE_LEN: usize = 32;
+
+/// Decodes a `X-Hub-Signature-256` header, `sha256=` followed by the hex encoded HMAC
+pub fn parse_signature(header: &str) -> Result<Vec<u8>, &'static str> {
+    let sig = header
+        .strip_prefix("sha256=")
+        .ok_or("Malformed X-Hub-Signature-256 header, expected it to start with sha256=")?;
+    let sig_bytes = hex::decode(sig)
+        .map_err(|_| "Corrupt X-Hub-Signature-256 header, failed to decode hex string")?;
+    if sig_bytes.len() != SIGNATURE_LEN {
+        return Err("Corrupt X-Hub-Signature-256 header, wrong signature length");
+    }
+    Ok(sig_bytes)
+}
+
+/// Accepts the payload if it was signed with any of the secrets, so the secret can be rotated
+/// without dropping webhooks. Without any secrets every payload is accepted.
 pub fn verify_signature(
-    secret: Option<&str>,
+    secrets: &[&str],
     signature: Option<&[u8]>,
     payload: &str,
 ) -> Result<(), actix_web::error::Error> {
-    if let Some(sekrit) = secret {
-        let Some(sig) = signature else {
-            return Err(actix_web::error::ErrorBadRequest(
-                "Expected signature in header",
-            ));
-        };
+    if secrets.is_empty() {
+        return Ok(());
+    }
 
-        //have to wrap it to stop timing attacks on comparison
-        let actual_signature = CtOutput::new(GenericArray::clone_from_slice(sig));
+    let Some(sig) = signature else {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Expected signature in header",
+        ));
+    };
+    if sig.len() != SIGNATURE_LEN {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Signature has the wrong length",
+        ));
+    }
 
+    //have to wrap it to stop timing attacks on comparison
+    let actual_signature = CtOutput::new(GenericArray::clone_from_slice(sig));
+
+    let matches = secrets.iter().any(|sekrit| {
         let mut mac = HmacSha256::new_from_slice(sekrit.as_bytes()).unwrap();
         mac.update(payload.as_bytes());
-        let computed_signature = mac.finalize();
+        actual_signature == mac.finalize()
+    });
 
-        if actual_signature.ne(&computed_signature) {
-            return Err(actix_web::error::ErrorBadRequest(
-                "Signature does not match!",
-            ));
-        };
+    if !matches {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Signature does not match!",
+        ));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::github_api::GithubEvent;
+    use actix_web::{http::StatusCode, FromRequest};
+
+    const PAYLOAD: &str = r#"{"action":"opened"}"#;
+
+    fn sign(secret: &str, payload: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn header(secret: &str, payload: &str) -> String {
+        format!("sha256={}", hex::encode(sign(secret, payload)))
+    }
+
+    fn status(result: Result<(), actix_web::Error>) -> StatusCode {
+        result.unwrap_err().as_response_error().status_code()
+    }
+
+    #[test]
+    fn parses_valid_header() {
+        let sig = parse_signature(&header("abcdef", PAYLOAD)).unwrap();
+        assert_eq!(sig, sign("abcdef", PAYLOAD));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for header in [
+            "",
+            "sha",
+            "sha256",
+            "sha1=abcdef",
+            "sha256=",
+            "sha256=zz",
+            "sha256=abc",
+            "sha256=abcdef",
+            &format!("sha256={}", "a".repeat(66)),
+        ] {
+            assert!(parse_signature(header).is_err(), "{header:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn accepts_matching_signature() {
+        let sig = sign("abcdef", PAYLOAD);
+        assert!(verify_signature(&["abcdef"], Some(sig.as_slice()), PAYLOAD).is_ok());
+    }
+
+    #[test]
+    fn accepts_any_rotated_secret() {
+        let secrets = ["new", "old"];
+        for secret in secrets {
+            let sig = sign(secret, PAYLOAD);
+            assert!(verify_signature(&secrets, Some(sig.as_slice()), PAYLOAD).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        let sig = sign("retired", PAYLOAD);
+        let result = verify_signature(&["new", "old"], Some(sig.as_slice()), PAYLOAD);
+        assert_eq!(status(result), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_tampered_payload() {
+        let sig = sign("abcdef", PAYLOAD);
+        let result = verify_signature(&["abcdef"], Some(sig.as_slice()), r#"{"action":"closed"}"#);
+        assert_eq!(status(result), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rejects_missing_or_short_signature() {
+        assert_eq!(
+            status(verify_signature(&["abcdef"], None, PAYLOAD)),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(verify_signature(&["abcdef"], Some(&[0xab][..]), PAYLOAD)),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn accepts_anything_without_secrets() {
+        assert!(verify_signature(&[], None, PAYLOAD).is_ok());
+    }
+
+    async fn extract(headers: &[(&str, &str)]) -> Result<GithubEvent, actix_web::Error> {
+        let mut req = actix_web::test::TestRequest::default();
+        for &header in headers {
+            req = req.insert_header(header);
+        }
+        GithubEvent::from_request(&req.to_http_request(), &mut actix_web::dev::Payload::None).await
+    }
+
+    #[actix_web::test]
+    async fn extracts_event_headers() {
+        let signature = header("abcdef", PAYLOAD);
+        let event = extract(&[
+            ("X-Github-Event", "pull_request"),
+            ("X-Hub-Signature-256", &signature),
+            ("X-GitHub-Delivery", "72d3162e-cc78-11e3-81ab-4c9367dc0958"),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(event.0, "pull_request");
+        assert_eq!(event.1, Some(sign("abcdef", PAYLOAD)));
+        assert_eq!(
+            event.2.as_deref(),
+            Some("72d3162e-cc78-11e3-81ab-4c9367dc0958")
+        );
+    }
+
+    #[actix_web::test]
+    async fn malformed_signature_header_is_a_bad_request() {
+        for signature in ["", "sha", "sha256=nothex"] {
+            let error = extract(&[
+                ("X-Github-Event", "pull_request"),
+                ("X-Hub-Signature-256", signature),
+            ])
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(
+                error.as_response_error().status_code(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn missing_event_header_is_a_bad_request() {
+        let error = extract(&[]).await.err().unwrap();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::{path::Path, time::Duration};
+
+use diffbot_lib::tracing;
+use eyre::{Context, Result};
+
+use crate::downloading::trim_blob_cache;
+
+/// How often old images and cached blobs are cleaned up
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn cleanup_scheduler(pool: Option<mysql_async::Pool>) {
+    let mut interval = actix_web::rt::time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(ref pool) = pool {
+            prune_expired_images(pool).await;
+        }
+
+        match actix_web::rt::task::spawn_blocking(prune_legacy_images).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Pruning images of the old layout: {e:?}"),
+            Err(e) => tracing::error!("Join Handle error: {e}"),
+        }
+
+        let max_size = crate::read_config().max_blob_cache_size;
+        match actix_web::rt::task::spawn_blocking(move || trim_blob_cache(max_size)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Trimming the blob cache: {e:?}"),
+            Err(e) => tracing::error!("Join Handle error: {e}"),
+        }
+    }
+}
+
+/// Deletes the images of closed PRs that are past their installation's retention days
+async fn prune_expired_images(pool: &mysql_async::Pool) {
+    let expired = match diffbot_lib::db::expired_jobs(pool).await {
+        Ok(expired) => expired,
+        Err(e) => {
+            tracing::error!("Looking up expired images: {e:?}");
+            return;
+        }
+    };
+    if !expired.is_empty() {
+        tracing::info!("Deleting the images of {} expired job(s)", expired.len());
+    }
+
+    for job in expired {
+        // Images are shared by every check run of the PR, they all expire together
+        let images = Path::new(".")
+            .join("images")
+            .join(job.installation_id.to_string())
+            .join(job.repo_id.to_string())
+            .join(job.pr_number.to_string());
+        if !remove_dir(&images).await {
+            continue;
+        }
+        if let Err(e) = diffbot_lib::db::mark_processed(pool, job.check_id).await {
+            tracing::error!("{e:?}");
+        }
+    }
+}
+
+/// Deletes what is left of the old `images/<installation>/<pr>` layout once it is past the
+/// installation's retention days, nothing is written there anymore.
+/// Those directories only hold images while the repository directories of the current layout only
+/// hold PR directories, so they can't be mistaken for each other.
+fn prune_legacy_images() -> Result<()> {
+    let policy = &crate::read_config().policy;
+    let images = Path::new(".").join("images");
+    let installations = match std::fs::read_dir(&images) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Reading {}", images.display())),
+    };
+
+    for installation in installations {
+        let installation = installation?;
+        let Some(retention_days) = installation
+            .file_name()
+            .to_str()
+            .and_then(|id| id.parse().ok())
+            .and_then(|id| policy.retention_days(id))
+        else {
+            continue;
+        };
+        let max_age = Duration::from_secs(u64::from(retention_days) * 24 * 60 * 60);
+
+        let path = installation.path();
+        if !path.is_dir() {
+            continue;
+        }
+        for directory in
+            std::fs::read_dir(&path).wrap_err_with(|| format!("Reading {}", path.display()))?
+        {
+            let directory = directory?.path();
+            if !is_expired_legacy_dir(&directory, max_age)? {
+                continue;
+            }
+            tracing::info!(
+                "Removing {}, left over from the old layout",
+                directory.display()
+            );
+            if let Err(e) = std::fs::remove_dir_all(&directory) {
+                tracing::error!("Removing {}: {e}", directory.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the directory only holds files, none of them changed within `max_age`
+fn is_expired_legacy_dir(directory: &Path, max_age: Duration) -> Result<bool> {
+    if !directory.is_dir() {
+        return Ok(false);
+    }
+    let entries = std::fs::read_dir(directory)
+        .wrap_err_with(|| format!("Reading {}", directory.display()))?;
+    for entry in entries {
+        let metadata = entry?.metadata()?;
+        if metadata.is_dir() || metadata.modified()?.elapsed().unwrap_or_default() < max_age {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Logs failures, a directory that is already gone counts as removed
+pub async fn remove_dir(path: &Path) -> bool {
+    match diffbot_lib::async_fs::remove_dir_all(path).await {
+        Ok(()) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => {
+            tracing::error!("Removing {}: {e}", path.display());
+            false
+        }
+    }
+}
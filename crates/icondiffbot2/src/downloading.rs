@@ -1,72 +1,138 @@
-use diffbot_lib::github::github_types::Repository;
-use eyre::Result;
-use octocrab::models::repos::Content;
+use diffbot_lib::{async_fs, github::github_types::Repository, metrics};
+use eyre::{Context, Result};
 use octocrab::models::InstallationId;
 use secrecy::ExposeSecret;
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
 
-//https://url.spec.whatwg.org/#c0-control-percent-encode-set
-const PATH_ENCODING: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
-    //query
-    .add(b' ')
-    .add(b'"')
-    .add(b'<')
-    .add(b'>')
-    .add(b'`')
-    //path
-    .add(b'?')
-    .add(b'}')
-    .add(b'{');
+/// Downloaded blobs are kept here by their git blob sha, so they are never fetched twice
+const BLOB_CACHE_DIR: &str = "./blob_cache";
 
-async fn find_content<S: AsRef<str>>(
-    installation: &InstallationId,
-    repo: &Repository,
-    filename: S,
-    commit: S,
-) -> Result<Content> {
-    let (owner, repo) = repo.name_tuple();
-    let items = octocrab::instance()
-        .installation(*installation)?
-        .repos(owner, repo)
-        .get_content()
-        .path(
-            percent_encoding::percent_encode(filename.as_ref().as_bytes(), PATH_ENCODING)
-                .to_string(),
-        )
-        .r#ref(commit.as_ref())
-        .send()
-        .await?
-        .take_items();
+static PART_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
-    if items.len() > 1 {
-        return Err(eyre::eyre!("Directory given to find_content"));
+/// Blobs are cached per repository, so they can be deleted when the app loses access to it
+pub fn blob_cache_dir(installation: InstallationId, repo_id: Option<u64>) -> PathBuf {
+    let dir = Path::new(BLOB_CACHE_DIR).join(installation.to_string());
+    match repo_id {
+        Some(repo_id) => dir.join(repo_id.to_string()),
+        None => dir,
     }
-
-    items
-        .into_iter()
-        .next()
-        .ok_or_else(|| eyre::eyre!("No content was found"))
 }
 
-pub async fn download_url<S: AsRef<str>>(
+/// Returns the blob's contents, from the disk cache if it was downloaded before
+///
+/// Uses the raw media type of the git blobs API, which unlike the contents API works for files
+/// over 1 MB. The download is aborted once it grows past `max_size` bytes.
+pub async fn download_blob(
     installation: &InstallationId,
     repo: &Repository,
-    filename: S,
-    commit: S,
-    client: reqwest::Client,
+    blob_sha: &str,
+    client: &reqwest::Client,
+    max_size: u64,
 ) -> Result<Vec<u8>> {
-    let target = find_content(installation, repo, filename, commit).await?;
+    let path = blob_cache_dir(*installation, Some(repo.id)).join(blob_sha);
+    if let Ok(bytes) = async_fs::read(&path).await {
+        // The modification time doubles as the last use, for trimming the cache
+        _ = std::fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        return Ok(bytes);
+    }
 
-    let download_url = target
-        .download_url
-        .as_ref()
-        .ok_or_else(|| eyre::eyre!("No download URL given by GitHub"))?;
     let (_, token) = octocrab::instance()
         .installation_and_token(*installation)
         .await?;
-    let response = client
-        .get(download_url)
+    metrics::github_api_call("download_blob");
+    let mut response = client
+        .get(format!(
+            "https://api.github.com/repos/{}/git/blobs/{blob_sha}",
+            repo.full_name()
+        ))
         .bearer_auth(token.expose_secret())
+        .header(reqwest::header::ACCEPT, "application/vnd.github.raw+json")
+        .header(reqwest::header::USER_AGENT, "IconDiffBot2")
         .send()
-        .await?;
-    Ok(response.bytes().await?.to_vec())
+        .await?
+        .error_for_status()
+        .wrap_err_with(|| format!("Downloading blob {blob_sha}"))?;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (bytes.len() + chunk.len()) as u64 > max_size {
+            return Err(eyre::eyre!(
+                "Blob {blob_sha} is over the limit of {max_size} bytes"
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if let Err(e) = write_cache(&path, &bytes).await {
+        tracing::warn!("Failed to cache blob {blob_sha}: {e:?}");
+    }
+
+    Ok(bytes)
+}
+
+/// Writes to a temporary file first so a crash never leaves a truncated blob behind
+async fn write_cache(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        async_fs::create_dir_all(dir).await?;
+    }
+    let temp_path = path.with_extension(format!(
+        "{}.part",
+        PART_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    async_fs::write(&temp_path, bytes).await?;
+    async_fs::rename(&temp_path, path).await?;
+    Ok(())
+}
+
+/// Deletes the least recently used blobs until the cache is at most `max_size` bytes
+pub fn trim_blob_cache(max_size: u64) -> Result<()> {
+    let mut blobs = Vec::new();
+    collect_blobs(Path::new(BLOB_CACHE_DIR), &mut blobs)?;
+
+    let mut size: u64 = blobs.iter().map(|(_, _, len)| len).sum();
+    if size <= max_size {
+        return Ok(());
+    }
+
+    blobs.sort_unstable_by_key(|(_, modified, _)| *modified);
+    let mut removed = 0;
+    for (path, _, len) in blobs {
+        if size <= max_size {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {
+                size -= len;
+                removed += 1;
+            }
+            Err(e) => tracing::warn!("Removing cached blob {}: {e}", path.display()),
+        }
+    }
+    tracing::info!("Removed {removed} blob(s) from the cache, {size} bytes left");
+    Ok(())
+}
+
+fn collect_blobs(dir: &Path, blobs: &mut Vec<(PathBuf, SystemTime, u64)>) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Reading {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_blobs(&entry.path(), blobs)?;
+        } else {
+            blobs.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+    }
+    Ok(())
 }
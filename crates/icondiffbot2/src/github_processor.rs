@@ -1,10 +1,20 @@
 use diffbot_lib::{
     github::{
-        github_api::CheckRun,
-        github_types::{ChangeType, FileDiff, Output, PullRequestEventPayload},
+        commands::parse_commands,
+        github_api::{get_pull_request, has_write_access, CheckRun},
+        github_types::{
+            ChangeType, FileDiff, Installation, IssueCommentEventPayload, Output, PullRequest,
+            PullRequestEventPayload, Repository,
+        },
         graphql::get_pull_files,
+        installation::{is_installation_event, parse_installation_event},
+        webhook,
+    },
+    job::{
+        limits::limit_files,
+        reconcile, tracker,
+        types::{Job, JobOptions},
     },
-    job::types::Job,
     tracing,
 };
 use eyre::Result;
@@ -12,73 +22,70 @@ use octocrab::models::InstallationId;
 
 use mysql_async::{params, prelude::Queryable};
 
-use crate::DataJobSender;
+use crate::{cleanup::remove_dir, downloading::blob_cache_dir, DataJobSender};
+
+/// Parses the arguments of an `/idb` comment, `None` if it's not a known command
+fn parse_command(args: &[&str]) -> Option<JobOptions> {
+    match args {
+        ["rerun"] => Some(Default::default()),
+        ["full"] => Some(JobOptions {
+            full_render: true,
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+fn label_options(pull: &PullRequest) -> JobOptions {
+    let labels = &crate::CONFIG.get().unwrap().labels;
+    JobOptions {
+        full_render: labels
+            .full_render
+            .as_deref()
+            .is_some_and(|name| pull.has_label(name)),
+        ..Default::default()
+    }
+}
 
 async fn handle_pull_request(
-    payload: PullRequestEventPayload,
+    payload: String,
     job_sender: DataJobSender,
     pool: actix_web::web::Data<Option<mysql_async::Pool>>,
 ) -> Result<()> {
+    let payload: PullRequestEventPayload = serde_json::from_str(&payload)?;
+
     let pool = pool.get_ref();
 
     match payload.action.as_str() {
         "opened" | "synchronize" => {
-            let check_run = CheckRun::create(
-                &payload.repository.full_name(),
-                &payload.pull_request.head.sha,
-                payload.installation.id,
-                Some("IconDiffBot2"),
+            submit_check(
+                payload.repository,
+                payload.pull_request,
+                &payload.installation,
+                job_sender,
+                pool,
+                None,
             )
-            .await?;
-
-            let (check_id, repo_id, pr_number) = (
-                check_run.id(),
-                payload.repository.id,
-                payload.pull_request.number,
-            );
-
-            let num_icons = handle_pull(payload, job_sender, check_run).await?;
-
-            if let Some(ref pool) = pool {
-                let mut conn = match pool.get_conn().await {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        tracing::error!("{:?}", e);
-                        return Ok(());
-                    }
-                };
-
-                if let Err(e) = conn
-                    .exec_drop(
-                        r"INSERT INTO jobs (
-                        check_id,
-                        repo_id,
-                        pr_number,
-                        merge_date,
-                        num_icons
-                    )
-                    VALUES(
-                        :check_id,
-                        :repo_id,
-                        :pr_number,
-                        :merge_date,
-                        :num_icons
-                    )
-                    ",
-                        params! {
-                            "check_id" => check_id,
-                            "repo_id" => repo_id,
-                            "pr_number" => pr_number,
-                            "merge_date" => None::<usize>,
-                            "num_icons" => num_icons,
-                        },
-                    )
-                    .await
-                {
-                    tracing::error!("{:?}", e);
-                };
+            .await
+        }
+        "labeled" | "unlabeled" => {
+            let labels = &crate::CONFIG.get().unwrap().labels;
+            if !payload
+                .label
+                .as_ref()
+                .is_some_and(|label| labels.is_relevant(&label.name))
+            {
+                return Ok(());
             }
-            Ok(())
+            submit_check(
+                payload.repository,
+                payload.pull_request,
+                &payload.installation,
+                job_sender,
+                pool,
+                None,
+            )
+            .await
         }
         "closed" => {
             if let Some(ref pool) = pool {
@@ -113,18 +120,146 @@ async fn handle_pull_request(
     }
 }
 
+async fn handle_issue_comment(
+    payload: String,
+    job_sender: DataJobSender,
+    pool: actix_web::web::Data<Option<mysql_async::Pool>>,
+) -> Result<()> {
+    let payload: IssueCommentEventPayload = serde_json::from_str(&payload)?;
+
+    if payload.action != "created" || payload.issue.pull_request.is_none() {
+        return Ok(());
+    }
+
+    let Some(options) = parse_commands(&payload.comment.body, "/idb")
+        .iter()
+        .find_map(|args| parse_command(args))
+    else {
+        return Ok(());
+    };
+
+    let full_name = payload.repository.full_name();
+
+    if !has_write_access(
+        &full_name,
+        &payload.comment.user.login,
+        payload.installation.id,
+    )
+    .await?
+    {
+        tracing::debug!(
+            "Ignoring command from {}, no write access",
+            payload.comment.user.login
+        );
+        return Ok(());
+    }
+
+    let pull_request =
+        get_pull_request(&full_name, payload.issue.number, payload.installation.id).await?;
+
+    submit_check(
+        payload.repository,
+        pull_request,
+        &payload.installation,
+        job_sender,
+        pool.get_ref(),
+        Some(options),
+    )
+    .await
+}
+
+async fn submit_check(
+    repository: Repository,
+    pull_request: PullRequest,
+    installation: &Installation,
+    job_sender: DataJobSender,
+    pool: &Option<mysql_async::Pool>,
+    requested: Option<JobOptions>,
+) -> Result<()> {
+    let check_run = CheckRun::create(
+        &repository.full_name(),
+        &pull_request.head.sha,
+        installation.id,
+        Some("IconDiffBot2"),
+    )
+    .await?;
+
+    let (check_id, repo_id, pr_number) = (check_run.id(), repository.id, pull_request.number);
+
+    let num_icons = handle_pull(
+        repository,
+        pull_request,
+        installation,
+        job_sender,
+        check_run,
+        requested,
+    )
+    .await?;
+
+    if let Some(ref pool) = pool {
+        let mut conn = match pool.get_conn().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = conn
+            .exec_drop(
+                r"INSERT INTO jobs (
+                check_id,
+                repo_id,
+                pr_number,
+                merge_date,
+                num_icons,
+                retention_days,
+                installation_id
+            )
+            VALUES(
+                :check_id,
+                :repo_id,
+                :pr_number,
+                :merge_date,
+                :num_icons,
+                :retention_days,
+                :installation_id
+            )
+            ",
+                params! {
+                    "check_id" => check_id,
+                    "repo_id" => repo_id,
+                    "pr_number" => pr_number,
+                    "merge_date" => None::<usize>,
+                    "num_icons" => num_icons,
+                    "retention_days" => crate::CONFIG.get().unwrap().policy.retention_days(installation.id),
+                    "installation_id" => installation.id,
+                },
+            )
+            .await
+        {
+            tracing::error!("{:?}", e);
+        };
+    }
+    Ok(())
+}
+
 async fn handle_pull(
-    payload: PullRequestEventPayload,
+    repository: Repository,
+    pull: PullRequest,
+    installation: &Installation,
     job_sender: DataJobSender,
     check_run: CheckRun,
+    requested: Option<JobOptions>,
 ) -> Result<usize> {
-    if payload
-        .pull_request
-        .title
-        .as_ref()
-        .ok_or_else(|| eyre::anyhow!("PR title is None"))?
-        .to_ascii_lowercase()
-        .contains("[idb ignore]")
+    // Explicitly asking for a render through a comment overrides the skip markers
+    if requested.is_none()
+        && pull
+            .title
+            .as_ref()
+            .ok_or_else(|| eyre::anyhow!("PR title is None"))?
+            .to_ascii_lowercase()
+            .contains("[idb ignore]")
     {
         let output = Output {
             title: "PR Ignored",
@@ -137,31 +272,36 @@ async fn handle_pull(
     }
 
     let conf = &crate::CONFIG.get().unwrap();
-    let (blacklist, contact) = (&conf.blacklist, &conf.blacklist_contact);
 
-    if blacklist.contains(&payload.repository.id) {
+    if let Some(skip_label) = conf
+        .labels
+        .skip
+        .as_deref()
+        .filter(|label| requested.is_none() && pull.has_label(label))
+    {
         let output = Output {
-            title: "Repo blacklisted",
-            summary: format!(
-                "Repository {} is blacklisted. {contact}",
-                payload.repository.full_name(),
-            ),
+            title: "PR Ignored",
+            summary: format!("This PR has the `{skip_label}` label. Aborting."),
             text: "".to_owned(),
         };
 
         check_run.mark_skipped(output).await?;
+        return Ok(0);
+    }
+
+    if let Err(denied) = conf.policy.check(
+        installation.id,
+        &repository,
+        (&conf.blacklist, &conf.blacklist_contact),
+    ) {
+        check_run.mark_skipped(denied.into_output()).await?;
 
         return Ok(0);
     }
 
-    let files = get_pull_files(
-        payload.repository.name_tuple(),
-        payload.installation.id,
-        &payload.pull_request,
-    )
-    .await?;
+    let files = get_pull_files(repository.name_tuple(), installation.id, &pull).await?;
 
-    let changed_dmis: Vec<FileDiff> = files
+    let mut changed_dmis: Vec<FileDiff> = files
         .into_iter()
         .filter(|e| e.filename.ends_with(".dmi"))
         .filter(|e| {
@@ -186,26 +326,147 @@ async fn handle_pull(
         return Ok(0);
     }
 
+    let truncated = limit_files(
+        &mut changed_dmis,
+        conf.limits(installation.id).max_files,
+        "icon files",
+    )
+    .into_iter()
+    .collect();
+
     check_run.mark_queued().await?;
 
-    let pull = payload.pull_request;
-    let installation = payload.installation;
+    let options = requested.unwrap_or_else(|| label_options(&pull));
 
     let job = Job {
-        repo: payload.repository,
+        repo: repository,
         base: pull.base,
         head: pull.head,
         pull_request: pull.number,
         files: changed_dmis,
         check_run,
         installation: InstallationId(installation.id),
+        options,
+        truncated,
     };
 
+    tracker::queued(&job);
     job_sender.send_async(job).await?;
 
     Ok(num_icons_diffed)
 }
 
+/// Picks up check runs left queued or in progress by a crash, queueing them again if they're
+/// still for the latest commit of an open PR and concluding them as interrupted otherwise
+pub async fn reconcile_stale_checks(pool: Option<mysql_async::Pool>, job_sender: DataJobSender) {
+    let Some(pool) = pool else {
+        tracing::debug!("No database configured, skipping check run reconciliation");
+        return;
+    };
+
+    let candidates: Vec<reconcile::Candidate> = match pool.get_conn().await {
+        Ok(mut conn) => match conn
+            .query(
+                r"SELECT check_id, repo_id, pr_number, installation_id FROM jobs
+                WHERE merge_date IS NULL
+                AND installation_id IS NOT NULL
+                ORDER BY check_id DESC
+                LIMIT 100",
+            )
+            .await
+        {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            tracing::error!("{:?}", e);
+            return;
+        }
+    };
+
+    let stale = match reconcile::find_stale(&candidates).await {
+        Ok(stale) => stale,
+        Err(e) => {
+            tracing::error!("Failed to look for stale check runs: {:?}", e);
+            return;
+        }
+    };
+
+    for stale in stale {
+        let check_id = stale.check_run.id();
+        let result = if stale.is_current() {
+            tracing::info!("Queueing stale check run {check_id} again");
+            handle_pull(
+                stale.repository,
+                stale.pull_request,
+                &stale.installation,
+                job_sender.clone(),
+                stale.check_run,
+                None,
+            )
+            .await
+            .map(|_| ())
+        } else {
+            tracing::info!("Concluding stale check run {check_id} as interrupted");
+            stale.mark_interrupted().await
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to reconcile check run {check_id}: {:?}", e);
+        }
+    }
+}
+
+/// Forgets repositories the app lost access to and deletes their images and cached blobs
+async fn handle_installation(
+    event: &str,
+    payload: String,
+    pool: actix_web::web::Data<Option<mysql_async::Pool>>,
+) -> Result<()> {
+    let Some(change) = parse_installation_event(event, &payload)? else {
+        return Ok(());
+    };
+
+    if let Some(ref pool) = pool.get_ref() {
+        let mut conn = pool.get_conn().await?;
+        for repository in change.removed.iter() {
+            tracing::info!("Lost access to {}, removing its jobs", repository.full_name);
+            if let Err(e) = conn
+                .exec_drop(
+                    r"DELETE FROM jobs WHERE repo_id=:repo_id",
+                    params! {
+                        "repo_id" => repository.id,
+                    },
+                )
+                .await
+            {
+                tracing::error!("{:?}", e);
+            }
+        }
+    }
+
+    let installation = InstallationId(change.installation);
+    let images = std::path::Path::new(".")
+        .join("images")
+        .join(change.installation.to_string());
+    // Uninstalls don't always list the repositories, so everything of the installation goes
+    if change.uninstalled {
+        tracing::info!("Uninstalled, removing {}", images.display());
+        remove_dir(&images).await;
+        remove_dir(&blob_cache_dir(installation, None)).await;
+        return Ok(());
+    }
+    for repository in change.removed {
+        tracing::info!("Removing the images of {}", repository.full_name);
+        remove_dir(&images.join(repository.id.to_string())).await;
+        remove_dir(&blob_cache_dir(installation, Some(repository.id))).await;
+    }
+
+    Ok(())
+}
+
 #[actix_web::post("/payload")]
 pub async fn process_github_payload_actix(
     event: diffbot_lib::github::github_api::GithubEvent,
@@ -213,27 +474,48 @@ pub async fn process_github_payload_actix(
     job_sender: DataJobSender,
     pool: actix_web::web::Data<Option<mysql_async::Pool>>,
 ) -> actix_web::Result<&'static str> {
-    // TODO: Handle reruns
-    if event.0 != "pull_request" {
+    if event.0 != "pull_request"
+        && event.0 != "issue_comment"
+        && event.0 != "ping"
+        && !is_installation_event(&event.0)
+    {
         return Ok("Not a pull request event");
     }
 
-    let secret = {
-        let conf = &crate::CONFIG.get().unwrap();
-        conf.secret.as_ref()
-    };
-
     diffbot_lib::verify::verify_signature(
-        secret.map(|v| v.as_str()),
+        &crate::read_config().webhook_secrets(),
         event.1.as_deref(),
         &payload,
     )?;
 
-    let payload: PullRequestEventPayload = serde_json::from_str(&payload)?;
+    if event.0 == "ping" {
+        let app_id = crate::CONFIG.get().unwrap().github.app_id;
+        webhook::check_ping(&payload, app_id, &["pull_request", "issue_comment"]).map_err(|e| {
+            tracing::error!("{:?}", e);
+            actix_web::error::ErrorBadRequest(e)
+        })?;
+        return Ok("Pong");
+    }
+
+    let delivery = event.2.as_deref();
+    if !webhook::claim_delivery(delivery) {
+        tracing::info!("Ignoring already processed delivery {:?}", delivery);
+        return Ok("Delivery already processed");
+    }
+
+    let result = if is_installation_event(&event.0) {
+        handle_installation(&event.0, payload, pool).await
+    } else if event.0 == "issue_comment" {
+        handle_issue_comment(payload, job_sender, pool).await
+    } else {
+        handle_pull_request(payload, job_sender, pool).await
+    };
 
-    handle_pull_request(payload, job_sender, pool)
-        .await
-        .map_err(actix_web::error::ErrorBadRequest)?;
+    result.map_err(|e| {
+        webhook::release_delivery(delivery);
+        tracing::error!("Error handling event: {:?}", e);
+        actix_web::error::ErrorBadRequest(e)
+    })?;
 
     Ok("")
 }
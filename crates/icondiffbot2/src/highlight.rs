@@ -0,0 +1,56 @@
+use crate::scaling::upscale_image;
+use image::{Pixel, Rgba, RgbaImage};
+
+const CHANGED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+
+/// Builds a strip with one highlight cell per direction and frame of a state
+///
+/// Changed pixels are marked red, unchanged ones are faded out. States whose dimensions or amount
+/// of frames differ are compared as if the smaller one was padded with transparent pixels.
+pub fn highlight_changes(
+    before: &[RgbaImage],
+    after: &[RgbaImage],
+    upscale: u32,
+) -> Option<RgbaImage> {
+    let cells = before.len().max(after.len()) as u32;
+    let (width, height) = before
+        .iter()
+        .chain(after)
+        .fold((0, 0), |(width, height), image| {
+            (width.max(image.width()), height.max(image.height()))
+        });
+
+    if cells == 0 || width == 0 || height == 0 {
+        return None;
+    }
+
+    let empty = RgbaImage::new(0, 0);
+    let mut strip = RgbaImage::new(width * cells, height);
+
+    for cell in 0..cells {
+        let before = before.get(cell as usize).unwrap_or(&empty);
+        let after = after.get(cell as usize).unwrap_or(&empty);
+        for y in 0..height {
+            for x in 0..width {
+                let before_pixel = pixel_at(before, x, y);
+                let after_pixel = pixel_at(after, x, y);
+                let pixel = if before_pixel == after_pixel {
+                    after_pixel.map_without_alpha(|c| c.saturating_add((255 - c) / 3))
+                } else {
+                    CHANGED
+                };
+                strip.put_pixel(cell * width + x, y, pixel);
+            }
+        }
+    }
+
+    Some(upscale_image(&strip, upscale))
+}
+
+/// Fully transparent pixels compare equal regardless of their color
+fn pixel_at(image: &RgbaImage, x: u32, y: u32) -> Rgba<u8> {
+    match image.get_pixel_checked(x, y) {
+        Some(pixel) if pixel[3] != 0 => *pixel,
+        _ => Rgba([0, 0, 0, 0]),
+    }
+}
@@ -1,126 +1,251 @@
 use crate::{
-    sha::{sha_to_iconfile, status_to_sha, IconFileWithName},
-    table_builder::OutputTableBuilder,
+    highlight::highlight_changes,
+    metadata::{file_changes, state_changes},
+    renames::{pair_renames, Fingerprint},
+    repo_config::{RepoConfig, REPO_CONFIG_PATH},
+    scaling::upscale_encoded,
+    sha::{load_icon_files, IconFilePair, IconFileWithName},
+    table_builder::{IconFileDiff, OutputTableBuilder, RenderedImage, StateDiff},
     CONFIG,
 };
-use diffbot_lib::{github::github_types::CheckOutputs, job::types::Job, tracing};
+use diffbot_lib::{
+    github::github_types::{CheckOutputs, Conclusion, FileDiff},
+    job::{
+        limits::Budget,
+        report::{JobReport, REPORT_FILE_NAME},
+        tracker::{self, CancelToken},
+        types::Job,
+    },
+    metrics, tracing,
+};
 use dmm_tools::dmi::render::{IconRenderer, RenderType};
-use dmm_tools::dmi::State;
 use eyre::{Context, Result};
 use hashbrown::HashSet;
+use image::{ImageFormat, RgbaImage};
 use rayon::prelude::*;
 use std::{
-    fs::File,
+    collections::HashMap,
     hash::{Hash, Hasher},
-    io::{BufWriter, Write},
     path::Path,
 };
 
 #[tracing::instrument]
-pub fn do_job(job: Job, client: reqwest::Client) -> Result<CheckOutputs> {
+pub fn do_job(job: Job, client: reqwest::Client, cancel: CancelToken) -> Result<CheckOutputs> {
     let handle = actix_web::rt::Runtime::new()?;
 
     handle.block_on(async { job.check_run.mark_started().await })?;
 
+    let mut report = JobReport::new(&job);
+
+    let (mut repo_config, config_notice, config_warning) =
+        match handle.block_on(RepoConfig::fetch(&job)) {
+            Ok((config, notice)) => (config, notice, None),
+            Err(e) => (
+                RepoConfig::default(),
+                None,
+                Some(format!(
+                    "Failed to load `{REPO_CONFIG_PATH}`, using defaults:\n```\n{e:?}\n```"
+                )),
+            ),
+        };
+    if job.options.full_render {
+        repo_config.show_unchanged = true;
+        repo_config.max_states = None;
+    }
+    let file_filter = repo_config.file_filter()?;
+
     let mut map = OutputTableBuilder::new();
 
-    job.files
+    let files: Vec<&FileDiff> = job
+        .files
         .iter()
-        .map(|dmi| {
-            (
-                sha_to_iconfile(
-                    &job,
-                    &dmi.filename,
-                    status_to_sha(&job, &dmi.status),
-                    client.clone(),
-                ),
-                dmi,
-            )
+        .filter(|dmi| file_filter.is_match(&dmi.filename))
+        .collect();
+
+    cancel.check()?;
+    let fetch_timer = tracker::enter_stage(job.check_run.id(), "fetch");
+    let icon_files = handle.block_on(load_icon_files(
+        &job,
+        &files,
+        &client,
+        (
+            crate::read_config().download_concurrency,
+            crate::read_config().max_icon_size,
+        ),
+    ))?;
+    drop(fetch_timer);
+
+    let limits = crate::read_config().limits(job.installation.0);
+    let output = Budget::new("bytes of output", limits.max_output_bytes);
+
+    let render_timer = tracker::enter_stage(job.check_run.id(), "render");
+    for (index, (dmi, file)) in files.iter().zip(&icon_files).enumerate() {
+        cancel.check()?;
+        tracker::progress(
+            job.check_run.id(),
+            format!("{index} of {} icon files compared", files.len()),
+        );
+        let states = state_count(file);
+        let diff = if states > limits.max_states {
+            IconFileDiff::error(format!(
+                "Skipped, the file has {states} icon states, more than the limit of {}",
+                limits.max_states
+            ))
+        } else {
+            compare(file, &repo_config).unwrap_or_else(|e| {
+                tracing::error!("Rendering {} failed: {e:?}", dmi.filename);
+                IconFileDiff::error(format!("Rendering failed:\n{e:?}"))
+            })
+        };
+        map.insert(dmi.filename.as_str(), diff);
+    }
+
+    // Pairing comes first so moves and renames past the cut are still found
+    map.pair_moves();
+    map.truncate(repo_config.max_states);
+
+    // Only the rows left after truncating are rendered to disk
+    let befores: HashMap<&str, &IconFileWithName> = files
+        .iter()
+        .zip(&icon_files)
+        .filter_map(|(dmi, (before, _))| {
+            Some((dmi.filename.as_str(), before.as_ref().ok()?.as_ref()?))
+        })
+        .collect();
+    for (index, (dmi, (_, after))) in files.iter().zip(&icon_files).enumerate() {
+        cancel.check()?;
+        tracker::progress(
+            job.check_run.id(),
+            format!("{index} of {} icon files rendered", files.len()),
+        );
+        let Some(diff) = map.get_mut(&dmi.filename) else {
+            continue;
+        };
+        if diff.error.is_some() {
+            continue;
+        }
+        if output.is_exceeded() {
+            *diff = IconFileDiff::error(output.error().to_string());
+            continue;
+        }
+        let after = after.as_ref().ok().and_then(Option::as_ref);
+        write_images(
+            &job,
+            &dmi.filename,
+            (&befores, after),
+            diff,
+            repo_config.upscale,
+            &output,
+        );
+    }
+    drop(render_timer);
+
+    cancel.check()?;
+
+    map.reports().for_each(|file| report.add_file(file));
+    if let Some(ref warning) = config_warning {
+        report.add_error(warning.as_str());
+    }
+    let report_url = write_report(&job, &mut report)?;
+
+    let summary_msg = repo_config
+        .summary_msg
+        .as_deref()
+        .unwrap_or(&crate::read_config().summary_msg);
+
+    let failed_notice = match map.failed_files() {
+        0 => None,
+        failed => Some(format!(
+            "**{failed} icon file(s) could not be rendered**, see the `ERROR` rows below."
+        )),
+    };
+
+    let truncated: Vec<String> = job
+        .truncated
+        .iter()
+        .cloned()
+        .chain(output.notice())
+        .collect();
+
+    let mut outputs = map.build(&format!(
+        "{}[Machine-readable report]({report_url})\n\n{summary_msg}",
+        failed_notice
+            .into_iter()
+            .chain(truncated.iter().map(|notice| format!("**{notice}**")))
+            .chain(config_warning)
+            .chain(config_notice)
+            .map(|notice| notice + "\n\n")
+            .collect::<String>(),
+    ))?;
+    if !truncated.is_empty() {
+        outputs.conclusion = Conclusion::Neutral;
+    }
+    Ok(outputs)
+}
+
+/// Icon states of the larger version of the file
+fn state_count((before, after): &IconFilePair) -> usize {
+    [before, after]
+        .into_iter()
+        .filter_map(|icon| icon.as_ref().ok()?.as_ref())
+        .map(|icon| {
+            icon.icon
+                .metadata
+                .states
+                .iter()
+                .map(|(_, states)| states.len())
+                .sum::<usize>()
         })
-        .try_for_each(|(file, dmi)| -> Result<()> {
-            let states = render(&job, file?)?;
-            map.insert(dmi.filename.as_str(), states);
-            Ok(())
-        })?;
+        .max()
+        .unwrap_or(0)
+}
 
-    map.build()
+/// Where the PR's images are stored under `./images`, by repository so they can be deleted
+/// when the app loses access to it
+fn image_prefix(job: &Job) -> String {
+    format!("{}/{}/{}", job.installation, job.repo.id, job.pull_request)
 }
 
+/// Writes the json report next to the images, returns the url it is hosted at.
+/// The images are shared by every check run of the PR, so each report gets its own directory.
+fn write_report<T: serde::Serialize>(job: &Job, report: &mut JobReport<T>) -> Result<String> {
+    let prefix = format!("{}/{}", image_prefix(job), job.check_run.id());
+    let directory = Path::new(".").join("images").join(&prefix);
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("Failed to create directory {directory:?}"))?;
+
+    let path = directory.join(REPORT_FILE_NAME);
+    std::fs::write(&path, report.finish()?)
+        .with_context(|| format!("Failed to write report to {path:?}"))?;
+
+    Ok(format!(
+        "{}/images/{prefix}/{REPORT_FILE_NAME}",
+        CONFIG.get().unwrap().web.file_hosting_url,
+    ))
+}
+
+/// Lists the states that differ between the two versions of the file, without writing any images
 #[tracing::instrument]
-fn render(
-    job: &Job,
-    diff: (Result<Option<IconFileWithName>>, Option<IconFileWithName>),
-) -> Result<(&'static str, Vec<String>)> {
-    // TODO: Alphabetize
+fn compare(diff: &IconFilePair, config: &RepoConfig) -> Result<IconFileDiff> {
     // TODO: Test more edge cases
     match diff {
-        (Ok(None), None) => Ok((
-            "UNCHANGED",
-            vec![format!(
-                include_str!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/templates/diff_line.txt"
-                )),
-                state_name = "",
-                old = "",
-                new = "",
-                change_text = "UNCHANGED",
-            )],
+        (Ok(None), Ok(None)) => Ok(IconFileDiff::unchanged()),
+        (Err(e), _) => Ok(IconFileDiff::error(format!(
+            "Old icon could not be loaded:\n{e:?}"
+        ))),
+        (_, Err(e)) => Ok(IconFileDiff::error(format!(
+            "New icon could not be loaded:\n{e:?}"
+        ))),
+
+        (Ok(None), Ok(Some(after))) => Ok(IconFileDiff::new(
+            "ADDED",
+            all_states(after, "Created").wrap_err("Failed to render new icon file")?,
         )),
-        (Err(e), _) => Ok((
-            "ERROR",
-            vec![format!(
-                include_str!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/templates/diff_line_error.txt"
-                )),
-                error = format_args!("Before icon render failed:\n{e:?}"),
-            )],
+        (Ok(Some(before)), Ok(None)) => Ok(IconFileDiff::new(
+            "DELETED",
+            all_states(before, "Deleted").wrap_err("Failed to render deleted icon file")?,
         )),
-
-        (Ok(None), Some(after)) => {
-            let urls = full_render(job, &after).wrap_err("Failed to render new icon file")?;
-
-            Ok((
-                "ADDED",
-                urls.par_iter()
-                    .map(|(state_name, url)| {
-                        format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = format_args!("{} ({})", state_name.1, state_name.0),
-                            old = "",
-                            new = url,
-                            change_text = "Created",
-                        )
-                    })
-                    .collect(),
-            ))
-        }
-        (Ok(Some(before)), None) => {
-            let urls = full_render(job, &before).wrap_err("Failed to render deleted icon file")?;
-
-            Ok((
-                "DELETED",
-                urls.par_iter()
-                    .map(|(state_name, url)| {
-                        format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = format_args!("{} ({})", state_name.1, state_name.0),
-                            old = url,
-                            new = "",
-                            change_text = "Deleted",
-                        )
-                    })
-                    .collect(),
-            ))
-        }
-        (Ok(Some(before)), Some(after)) => {
+        (Ok(Some(before)), Ok(Some(after))) => {
             let before_states: HashSet<(usize, &str), ahash::RandomState> = before
                 .icon
                 .metadata
@@ -146,69 +271,33 @@ fn render(
                 })
                 .collect();
 
-            let prefix = format!("{}/{}", job.installation, job.pull_request);
-
             let before_renderer = IconRenderer::new(&before.icon);
             let after_renderer = IconRenderer::new(&after.icon);
 
-            let mut table: Vec<String> = before_states
+            let table: Vec<StateDiff> = before_states
                 .par_symmetric_difference(&after_states)
-                .map(|state| {
-                    if before_states.contains(state) {
-                        let (name, url) = render_state(
-                            &prefix,
-                            &before,
-                            before
-                                .icon
-                                .metadata
-                                .get_icon_state((state.0, state.1).into())
-                                .map(|(_, state_item)| (state.0, state_item))
-                                .unwrap(),
-                            &before_renderer,
-                        )
-                        .with_context(|| format!("Failed to render before-state {state:?}"))?;
-                        Ok(format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = format_args!("{} ({})", name.1, name.0),
-                            old = url,
-                            new = "",
-                            change_text = "Deleted",
-                        ))
+                .map(|&(duplicate, name)| {
+                    let (status, renderer) = if before_states.contains(&(duplicate, name)) {
+                        ("Deleted", &before_renderer)
                     } else {
-                        let (name, url) = render_state(
-                            &prefix,
-                            &after,
-                            after
-                                .icon
-                                .metadata
-                                .get_icon_state((state.0, state.1).into())
-                                .map(|(_, state_item)| (state.0, state_item))
-                                .unwrap(),
-                            &after_renderer,
-                        )
-                        .with_context(|| format!("Failed to render after-state {state:?}"))?;
-                        Ok(format!(
-                            include_str!(concat!(
-                                env!("CARGO_MANIFEST_DIR"),
-                                "/templates/diff_line.txt"
-                            )),
-                            state_name = format_args!("{} ({})", name.1, name.0),
-                            old = "",
-                            new = url,
-                            change_text = "Created",
-                        ))
-                    }
+                        ("Created", &after_renderer)
+                    };
+                    let images = renderer
+                        .render_to_images((duplicate, name).into())
+                        .with_context(|| format!("Failed to render state {name}"))?;
+                    Ok(StateDiff {
+                        fingerprint: Some(Fingerprint::new(images)),
+                        ..StateDiff::new(name.to_owned(), duplicate, status)
+                    })
                 })
-                .filter_map(|r: Result<String, eyre::Error>| {
+                .filter_map(|r: Result<StateDiff, eyre::Error>| {
                     r.map_err(|e| {
                         tracing::error!("Error encountered during parse: {e}");
                     })
                     .ok()
                 })
                 .collect();
+            let mut table = pair_renames(table);
 
             table.par_extend(
                 before_states
@@ -219,75 +308,142 @@ fn render(
                             before.icon.metadata.get_icon_state(state_index).unwrap();
                         let after_state = after.icon.metadata.get_icon_state(state_index).unwrap();
 
-                        let difference = {
-                            // #[cfg(debug_assertions)]
-                            // dbg!(before_state, after_state);
-                            if before_state.1 != after_state.1 {
-                                true
+                        let before_state_render = before_renderer.render_to_images(state_index)?;
+                        let after_state_render = after_renderer.render_to_images(state_index)?;
+                        let difference = before_state.1 != after_state.1
+                            || before_state_render != after_state_render;
+
+                        if difference || config.show_unchanged {
+                            let highlight = if difference {
+                                highlight_changes(
+                                    &before_state_render,
+                                    &after_state_render,
+                                    config.upscale,
+                                )
                             } else {
-                                let before_state_render =
-                                    before_renderer.render_to_images(state_index)?;
-                                let after_state_render =
-                                    after_renderer.render_to_images(state_index)?;
-                                before_state_render != after_state_render
-                            }
-                        };
-
-                        if difference {
-                            let (_, before_url) = render_state(
-                                &prefix,
-                                &before,
-                                (duplication_index, before_state.1),
-                                &before_renderer,
-                            )
-                            .with_context(|| {
-                                format!("Failed to render modified before-state {state}")
-                            })?;
-                            let (_, after_url) = render_state(
-                                &prefix,
-                                &after,
-                                (duplication_index, after_state.1),
-                                &after_renderer,
-                            )
-                            .with_context(|| {
-                                format!("Failed to render modified before-state {state}")
-                            })?;
-
-                            Ok(format!(
-                                include_str!(concat!(
-                                    env!("CARGO_MANIFEST_DIR"),
-                                    "/templates/diff_line.txt"
-                                )),
-                                state_name = state,
-                                old = before_url,
-                                new = after_url,
-                                change_text = "Modified",
-                            ))
+                                None
+                            };
+
+                            Ok(Some(StateDiff {
+                                changes: state_changes(&before_state.1, &after_state.1),
+                                highlight,
+                                ..StateDiff::new(
+                                    state.to_owned(),
+                                    duplication_index,
+                                    if difference { "Modified" } else { "Unchanged" },
+                                )
+                            }))
                         } else {
-                            Ok("".to_string())
+                            Ok(None)
                         }
                     })
-                    .filter_map(|r: Result<String, eyre::Error>| {
+                    .filter_map(|r: Result<Option<StateDiff>, eyre::Error>| {
                         r.map_err(|e| {
                             tracing::error!("Error encountered during parse: {}", e);
                         })
                         .ok()
-                    })
-                    .filter(|s| !s.is_empty()),
+                        .flatten()
+                    }),
             );
 
-            Ok(("MODIFIED", table))
+            Ok(
+                IconFileDiff::new("MODIFIED", table).with_changes(file_changes(
+                    (before.icon.metadata.width, before.icon.metadata.height),
+                    (after.icon.metadata.width, after.icon.metadata.height),
+                )),
+            )
         }
     }
 }
 
+/// Renders and writes the images of the rows that are shown, rows that fail to render are dropped
+#[tracing::instrument(skip(befores))]
+fn write_images(
+    job: &Job,
+    path: &str,
+    (befores, after): (&HashMap<&str, &IconFileWithName>, Option<&IconFileWithName>),
+    diff: &mut IconFileDiff,
+    upscale: u32,
+    output: &Budget,
+) {
+    let prefix = image_prefix(job);
+    let before = befores.get(path).copied();
+
+    diff.states = std::mem::take(&mut diff.states)
+        .into_par_iter()
+        .map(|mut state| {
+            if state.status != "Created" {
+                let (file, duplicate, name) = match state.old_state {
+                    Some(ref old) => (
+                        old.file.as_deref().unwrap_or(path),
+                        old.duplicate,
+                        old.name.as_str(),
+                    ),
+                    None => (path, state.duplicate, state.name.as_str()),
+                };
+                let source = befores
+                    .get(file)
+                    .ok_or_else(|| eyre::eyre!("Old version of {file} is missing"))?;
+                state.old = Some(
+                    render_state(&prefix, source, (duplicate, name), upscale, output)
+                        .with_context(|| format!("Failed to render before-state {name}"))?,
+                );
+            }
+            if state.status != "Deleted" {
+                let target =
+                    after.ok_or_else(|| eyre::eyre!("New version of {path} is missing"))?;
+                state.new = Some(
+                    render_state(
+                        &prefix,
+                        target,
+                        (state.duplicate, &state.name),
+                        upscale,
+                        output,
+                    )
+                    .with_context(|| format!("Failed to render after-state {}", state.name))?,
+                );
+            }
+            if let (Some(highlight), Some(before), Some(after)) =
+                (state.highlight.take(), before, after)
+            {
+                state.diff = Some(
+                    write_highlight(
+                        &prefix,
+                        (before, after),
+                        (state.duplicate, &state.name),
+                        &highlight,
+                        output,
+                    )
+                    .with_context(|| {
+                        format!("Failed to write highlight for state {}", state.name)
+                    })?,
+                );
+            }
+            Ok(state)
+        })
+        .filter_map(|r: Result<StateDiff, eyre::Error>| {
+            r.map_err(|e| {
+                tracing::error!("Error encountered during render: {e:?}");
+            })
+            .ok()
+        })
+        .collect();
+}
+
 #[tracing::instrument]
-fn render_state<'a, S: AsRef<str> + std::fmt::Debug>(
+fn render_state<S: AsRef<str> + std::fmt::Debug>(
     prefix: S,
     target: &IconFileWithName,
-    (index, state): (usize, &State),
-    renderer: &IconRenderer<'a>,
-) -> Result<((usize, String), String)> {
+    (index, name): (usize, &str),
+    upscale: u32,
+    output: &Budget,
+) -> Result<RenderedImage> {
+    let (_, state) = target
+        .icon
+        .metadata
+        .get_icon_state((index, name).into())
+        .ok_or_else(|| eyre::eyre!("State {name} ({index}) not found"))?;
+
     let directory = Path::new(".").join("images").join(prefix.as_ref());
     // Always remember to mkdir -p your paths
     std::fs::create_dir_all(&directory)
@@ -301,47 +457,110 @@ fn render_state<'a, S: AsRef<str> + std::fmt::Debug>(
     state.name.hash(&mut hasher);
     let filename = hasher.finish().to_string();
 
-    // TODO: Calculate file extension separately so that we can Error here if we overwrite a file
-    let mut path = directory.join(&filename);
-
+    let renderer = IconRenderer::new(&target.icon);
     let render_guard = renderer
         .prepare_render_state(state, index)
         .with_context(|| format!("Failed to create render guard for state {}", state.name))?;
 
-    let extension = match render_guard.render_type {
-        RenderType::Png => "png",
-        RenderType::Gif => "gif",
+    let (extension, format) = match render_guard.render_type {
+        RenderType::Png => ("png", ImageFormat::Png),
+        RenderType::Gif => ("gif", ImageFormat::Gif),
     };
-    path.set_extension(extension);
-
-    let mut buffer = BufWriter::new(File::create(&path)?);
 
+    let mut rendered = Vec::new();
     render_guard
-        .render(&mut buffer)
-        .with_context(|| format!("Failed to render state {} to file {:?}", state.name, &path))?;
+        .render(&mut rendered)
+        .with_context(|| format!("Failed to render state {}", state.name))?;
+    if !output.take(rendered.len() as u64) {
+        return Err(output.error());
+    }
 
-    let url = format!(
-        "{}/images/{}/{filename}.{extension}",
+    let url_base = format!(
+        "{}/images/{}",
         CONFIG.get().unwrap().web.file_hosting_url,
         prefix.as_ref(),
     );
 
-    buffer.flush().with_context(|| {
-        format!("Failed to flush BufWriter to disk for state {state:?} at {path:?}")
+    // TODO: Calculate file extension separately so that we can Error here if we overwrite a file
+    let path = directory.join(format!("{filename}.{extension}"));
+    std::fs::write(&path, &rendered)
+        .with_context(|| format!("Failed to write state {} to file {path:?}", state.name))?;
+    metrics::image_stored(rendered.len());
+    let original_url = format!("{url_base}/{filename}.{extension}");
+
+    if upscale <= 1 {
+        return Ok(RenderedImage {
+            url: original_url,
+            original: None,
+        });
+    }
+
+    let upscaled = upscale_encoded(&rendered, format, upscale)
+        .with_context(|| format!("Failed to upscale state {}", state.name))?;
+    if !output.take(upscaled.len() as u64) {
+        return Err(output.error());
+    }
+    let path = directory.join(format!("{filename}-{upscale}x.{extension}"));
+    std::fs::write(&path, &upscaled).with_context(|| {
+        format!(
+            "Failed to write upscaled state {} to file {path:?}",
+            state.name
+        )
     })?;
+    metrics::image_stored(upscaled.len());
 
-    Ok(((index, state.name.clone()), url))
+    Ok(RenderedImage {
+        url: format!("{url_base}/{filename}-{upscale}x.{extension}"),
+        original: Some(original_url),
+    })
 }
 
-#[tracing::instrument]
-fn full_render(job: &Job, target: &IconFileWithName) -> Result<Vec<((usize, String), String)>> {
-    let icon = &target.icon;
+/// Writes the highlight image of a modified state, returns the url it is hosted at
+fn write_highlight(
+    prefix: &str,
+    (before, after): (&IconFileWithName, &IconFileWithName),
+    (index, name): (usize, &str),
+    highlight: &RgbaImage,
+    output: &Budget,
+) -> Result<String> {
+    if output.is_exceeded() {
+        return Err(output.error());
+    }
 
-    let renderer = IconRenderer::new(icon);
+    let directory = Path::new(".").join("images").join(prefix);
+    std::fs::create_dir_all(&directory)
+        .with_context(|| format!("Failed to create directory {directory:?}"))?;
 
-    let prefix = format!("{}/{}", job.installation, job.pull_request);
+    let mut hasher = ahash::AHasher::default();
+    before.sha.hash(&mut hasher);
+    after.sha.hash(&mut hasher);
+    after.full_name.hash(&mut hasher);
+    index.hash(&mut hasher);
+    name.hash(&mut hasher);
+    let filename = format!("{}-diff.png", hasher.finish());
+
+    let path = directory.join(&filename);
+    highlight
+        .save_with_format(&path, ImageFormat::Png)
+        .with_context(|| format!("Failed to save highlight to {path:?}"))?;
+    if let Ok(written) = std::fs::metadata(&path) {
+        metrics::image_stored(written.len() as usize);
+        output.take(written.len());
+    }
 
-    let vec: Vec<((usize, String), String)> = icon
+    Ok(format!(
+        "{}/images/{prefix}/{filename}",
+        CONFIG.get().unwrap().web.file_hosting_url,
+    ))
+}
+
+/// Every state of the file as created or deleted rows
+#[tracing::instrument]
+fn all_states(target: &IconFileWithName, status: &'static str) -> Result<Vec<StateDiff>> {
+    let renderer = IconRenderer::new(&target.icon);
+
+    let vec: Vec<StateDiff> = target
+        .icon
         .metadata
         .states
         .par_values()
@@ -353,10 +572,15 @@ fn full_render(job: &Job, target: &IconFileWithName) -> Result<Vec<((usize, Stri
         })
         .flatten()
         .map(|(idx, state)| {
-            render_state(&prefix, target, (idx, state), &renderer)
-                .with_context(|| format!("Failed to render state {}", state.name))
+            let images = renderer
+                .render_to_images((idx, state.name.as_str()).into())
+                .with_context(|| format!("Failed to render state {}", state.name))?;
+            Ok(StateDiff {
+                fingerprint: Some(Fingerprint::new(images)),
+                ..StateDiff::new(state.name.clone(), idx, status)
+            })
         })
-        .filter_map(|r: Result<((usize, String), String), eyre::Error>| {
+        .filter_map(|r: Result<StateDiff, eyre::Error>| {
             r.map_err(|e| {
                 tracing::error!("Error encountered during parse: {e}");
             })
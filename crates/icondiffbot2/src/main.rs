@@ -1,7 +1,13 @@
+mod cleanup;
 mod downloading;
 mod github_processor;
+mod highlight;
 mod job_processor;
+mod metadata;
+mod renames;
+mod repo_config;
 mod runner;
+mod scaling;
 mod sha;
 mod table_builder;
 
@@ -9,9 +15,10 @@ use diffbot_lib::{
     async_fs,
     job::types::{Job, JobSender},
 };
+use eyre::WrapErr;
 use mysql_async::prelude::Queryable;
 use octocrab::OctocrabBuilder;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::sync::OnceLock;
 use std::{
     fs::File,
@@ -55,6 +62,45 @@ pub struct GrafanaLoki {
     url: String,
 }
 
+/// PR labels that control the bot, unset labels are ignored
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct LabelConfig {
+    pub skip: Option<String>,
+    pub full_render: Option<String>,
+}
+
+impl LabelConfig {
+    pub fn is_relevant(&self, label: &str) -> bool {
+        [&self.skip, &self.full_render]
+            .into_iter()
+            .flatten()
+            .any(|name| name.eq_ignore_ascii_case(label))
+    }
+}
+
+/// Guardrails against enormous PRs, the check run concludes as neutral if one of them is hit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Changed icon files rendered per job
+    pub max_files: usize,
+    /// Icon states per file, larger files are not rendered
+    pub max_states: usize,
+    /// Bytes of images stored per job
+    pub max_output_bytes: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_files: 200,
+            max_states: 2000,
+            max_output_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub github: GithubConfig,
@@ -68,8 +114,85 @@ pub struct Config {
     #[serde(default = "default_msg")]
     pub summary_msg: String,
     pub secret: Option<String>,
+    /// Secrets that are still accepted while the webhook secret is being rotated
+    #[serde(default)]
+    pub previous_secrets: Vec<String>,
+    /// Allows running without a webhook secret, for local testing
+    #[serde(default)]
+    pub dev_mode: bool,
+    pub admin_token: Option<String>,
+    #[serde(default = "default_grace_period")]
+    pub shutdown_grace_period: u64,
+    #[serde(default = "default_job_timeout")]
+    pub job_timeout: u64,
+    /// Job timeouts for specific repositories, keyed by `owner/repo`
+    #[serde(default)]
+    pub repo_job_timeouts: std::collections::HashMap<String, u64>,
     pub db_url: Option<String>,
     pub grafana_loki: Option<GrafanaLoki>,
+    #[serde(default)]
+    pub labels: LabelConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub policy: diffbot_lib::policy::PolicyConfig,
+    #[serde(default = "default_download_concurrency")]
+    pub download_concurrency: usize,
+    #[serde(default = "default_max_icon_size")]
+    pub max_icon_size: u64,
+    #[serde(default = "default_max_blob_cache_size")]
+    pub max_blob_cache_size: u64,
+}
+
+fn default_download_concurrency() -> usize {
+    8
+}
+
+fn default_max_icon_size() -> u64 {
+    20 * 1024 * 1024
+}
+
+fn default_max_blob_cache_size() -> u64 {
+    2 * 1024 * 1024 * 1024
+}
+
+impl Config {
+    /// The current webhook secret followed by the ones being rotated out
+    pub fn webhook_secrets(&self) -> Vec<&str> {
+        self.secret
+            .iter()
+            .chain(self.previous_secrets.iter())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The limits with the installation's policy overrides applied
+    pub fn limits(&self, installation: u64) -> LimitsConfig {
+        self.policy
+            .limits(installation, &self.limits)
+            .unwrap_or_else(|e| {
+                diffbot_lib::tracing::error!("{e:?}");
+                self.limits.clone()
+            })
+    }
+
+    /// How long a job for the repository may run before it is stopped
+    pub fn job_timeout(&self, full_repo: &str) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.repo_job_timeouts
+                .get(full_repo)
+                .copied()
+                .unwrap_or(self.job_timeout),
+        )
+    }
+}
+
+fn default_grace_period() -> u64 {
+    300
+}
+
+fn default_job_timeout() -> u64 {
+    7200
 }
 
 fn default_log_level() -> String {
@@ -88,7 +211,13 @@ fn init_config(path: &Path) -> eyre::Result<&'static Config> {
     let mut config_str = String::new();
     File::open(path)?.read_to_string(&mut config_str)?;
 
-    let config = toml::from_str(&config_str)?;
+    let config: Config = toml::from_str(&config_str)?;
+    config.policy.validate(&config.limits)?;
+    if config.secret.as_deref().unwrap_or_default().is_empty() && !config.dev_mode {
+        eyre::bail!(
+            "No webhook secret is set, set `secret` or enable `dev_mode` to accept unsigned payloads"
+        );
+    }
 
     CONFIG.set(config).expect("Failed to set config");
     Ok(CONFIG.get().unwrap())
@@ -134,7 +263,7 @@ async fn main() -> eyre::Result<()> {
 
     let config_path = Path::new(".").join("config.toml");
     let config =
-        init_config(&config_path).unwrap_or_else(|_| panic!("Failed to read {config_path:?}"));
+        init_config(&config_path).wrap_err_with(|| format!("Failed to load {config_path:?}"))?;
 
     let (layer, tasks) = if let Some(ref loki_config) = config.grafana_loki {
         let (layer, tasks) = tracing_loki::builder()
@@ -167,6 +296,7 @@ async fn main() -> eyre::Result<()> {
     async_fs::create_dir_all("./images").await.unwrap();
 
     let (job_sender, job_receiver) = flume::unbounded();
+    diffbot_lib::metrics::observe_queue(job_receiver.clone());
 
     let pool = config
         .db_url
@@ -183,12 +313,15 @@ async fn main() -> eyre::Result<()> {
                 `merge_date` DATETIME NULL DEFAULT NULL,
                 `num_icons` BIGINT(20) NOT NULL DEFAULT b'0',
                 `processed` BIT(1) NOT NULL DEFAULT b'0',
+                `retention_days` INT(11) NULL DEFAULT NULL,
+                `installation_id` BIGINT(20) NULL DEFAULT NULL,
                 PRIMARY KEY (`check_id`) USING BTREE,
                 INDEX `merge_date` (`processed`) USING BTREE,
                 INDEX `processed` (`processed`) USING BTREE
             ) COLLATE='utf8mb4_general_ci' ENGINE=InnoDB;",
         )
         .await?;
+        diffbot_lib::db::add_missing_columns(&mut conn).await?;
     }
 
     actix_web::rt::spawn(runner::handle_jobs(
@@ -197,6 +330,31 @@ async fn main() -> eyre::Result<()> {
         reqwest_client,
     ));
 
+    match diffbot_lib::job::shutdown::restore().await {
+        Ok(jobs) => {
+            for job in jobs {
+                job_sender.send(job)?;
+            }
+        }
+        Err(e) => diffbot_lib::tracing::error!("Failed to restore unfinished jobs: {e:?}"),
+    }
+
+    actix_web::rt::spawn(cleanup::cleanup_scheduler(pool.clone()));
+
+    actix_web::rt::spawn(github_processor::reconcile_stale_checks(
+        pool.clone(),
+        actix_web::web::Data::new(job_sender.clone()),
+    ));
+
+    let admin = {
+        let job_sender = job_sender.clone();
+        actix_web::web::Data::new(diffbot_lib::admin::Admin::new(
+            "IconDiffBot2",
+            config.admin_token.clone(),
+            move |job| Ok(job_sender.send(job)?),
+        ))
+    };
+
     let job_sender: DataJobSender = actix_web::web::Data::new(job_sender);
 
     actix_web::HttpServer::new(move || {
@@ -217,12 +375,21 @@ async fn main() -> eyre::Result<()> {
             .app_data(string_config)
             .app_data(job_sender.clone())
             .app_data(pool)
+            .app_data(admin.clone())
             .service(index)
+            .service(diffbot_lib::admin::scope())
+            .service(diffbot_lib::metrics::metrics_endpoint)
             .service(github_processor::process_github_payload_actix)
             .service(actix_files::Files::new("/images", "./images"))
     })
     .bind((config.web.address.as_ref(), config.web.port))?
     .run()
     .await?;
-    Ok(())
+
+    diffbot_lib::job::shutdown::shutdown(std::time::Duration::from_secs(
+        config.shutdown_grace_period,
+    ))
+    .await;
+    // A render that outlived the grace period would keep the runtime from shutting down
+    std::process::exit(0)
 }
@@ -0,0 +1,32 @@
+use dmm_tools::dmi::State;
+use std::fmt::Debug;
+
+/// Lists the metadata differences between two versions of a state, empty if only pixels changed
+pub fn state_changes(before: &State, after: &State) -> Vec<String> {
+    let mut changes = Vec::new();
+    compare(&mut changes, "Directions", &before.dirs, &after.dirs);
+    compare(&mut changes, "Frames", &before.frames, &after.frames);
+    compare(&mut changes, "Loop", &before.loop_flag, &after.loop_flag);
+    compare(&mut changes, "Rewind", &before.rewind, &after.rewind);
+    compare(&mut changes, "Movement", &before.movement, &after.movement);
+    compare(&mut changes, "Hotspot", &before.hotspot, &after.hotspot);
+    changes
+}
+
+/// Lists the differences of settings that apply to every state in the file
+pub fn file_changes(before_size: (u32, u32), after_size: (u32, u32)) -> Vec<String> {
+    let mut changes = Vec::new();
+    if before_size != after_size {
+        changes.push(format!(
+            "Icon size: `{}x{}` → `{}x{}`",
+            before_size.0, before_size.1, after_size.0, after_size.1
+        ));
+    }
+    changes
+}
+
+fn compare<T: PartialEq + Debug>(changes: &mut Vec<String>, what: &str, before: &T, after: &T) {
+    if before != after {
+        changes.push(format!("{what}: `{before:?}` → `{after:?}`"));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::table_builder::{sort_states, IconFileDiff, OldState, StateDiff};
+use image::RgbaImage;
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
+
+/// Percentage of pixels that may differ for two states to still count as the same state
+const MAX_CHANGED_PERCENT: usize = 2;
+/// Near identical states are only looked for if neither side has more unpaired states than this,
+/// every candidate pair costs a pixel by pixel comparison
+const MAX_NEAR_CANDIDATES: usize = 256;
+
+/// Every rendered direction and frame of a created or deleted state, kept until it is paired
+#[derive(Debug)]
+pub struct Fingerprint {
+    hash: u64,
+    images: Vec<RgbaImage>,
+}
+
+impl Fingerprint {
+    pub fn new(images: Vec<RgbaImage>) -> Self {
+        let mut hasher = ahash::AHasher::default();
+        for image in images.iter() {
+            image.dimensions().hash(&mut hasher);
+            image.as_raw().hash(&mut hasher);
+        }
+        Self {
+            hash: hasher.finish(),
+            images,
+        }
+    }
+
+    /// Whether the states are pixel identical, or with `exact` unset, near identical: same size
+    /// and amount of frames with at most [`MAX_CHANGED_PERCENT`] of the pixels changed
+    fn matches(&self, other: &Self, exact: bool) -> bool {
+        if self.hash == other.hash && self.images == other.images {
+            return true;
+        }
+        if exact
+            || self.images.len() != other.images.len()
+            || self
+                .images
+                .iter()
+                .zip(&other.images)
+                .any(|(a, b)| a.dimensions() != b.dimensions())
+        {
+            return false;
+        }
+
+        let total: usize = self
+            .images
+            .iter()
+            .map(|image| (image.width() * image.height()) as usize)
+            .sum();
+        let max_changed = total * MAX_CHANGED_PERCENT / 100;
+        let mut changed = 0;
+        for (a, b) in self
+            .images
+            .iter()
+            .zip(&other.images)
+            .flat_map(|(a, b)| a.pixels().zip(b.pixels()))
+        {
+            // Transparent pixels count as equal whatever their colour
+            if a != b && (a.0[3] != 0 || b.0[3] != 0) {
+                changed += 1;
+                if changed > max_changed {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Whether the near identical pass is cheap enough to run for the states left after the exact one
+fn near_pass_allowed(created: usize, deleted: usize) -> bool {
+    created.max(deleted) <= MAX_NEAR_CANDIDATES
+}
+
+/// Notes near identical pairs, their images differ even though no highlight is shown
+fn pairing_note(exact: bool) -> &'static str {
+    if exact {
+        ""
+    } else {
+        ", with small pixel changes"
+    }
+}
+
+/// Merges deleted and created states of the same file that look the same into "Renamed" rows.
+/// Pixel identical pairs are matched before near identical ones, which are skipped for files
+/// with too many unpaired states.
+pub fn pair_renames(mut states: Vec<StateDiff>) -> Vec<StateDiff> {
+    // Keeps the pairing stable when several states look the same
+    sort_states(&mut states);
+    let (mut deleted, mut others): (Vec<_>, Vec<_>) = states
+        .into_iter()
+        .partition(|state| state.status == "Deleted" && state.fingerprint.is_some());
+
+    for exact in [true, false] {
+        let created = others
+            .iter()
+            .filter(|state| state.status == "Created" && state.fingerprint.is_some())
+            .count();
+        if !exact && !near_pass_allowed(created, deleted.len()) {
+            break;
+        }
+        for state in others.iter_mut() {
+            let Some(ref fingerprint) = state.fingerprint else {
+                continue;
+            };
+            if state.status != "Created" {
+                continue;
+            }
+            let Some(position) = deleted.iter().position(|old| {
+                old.fingerprint
+                    .as_ref()
+                    .is_some_and(|old| old.matches(fingerprint, exact))
+            }) else {
+                continue;
+            };
+            let old = deleted.remove(position);
+            state.status = "Renamed";
+            state.changes.push(format!(
+                "Renamed from `{} ({})`{}",
+                old.name,
+                old.duplicate,
+                pairing_note(exact)
+            ));
+            state.old_state = Some(OldState {
+                file: None,
+                duplicate: old.duplicate,
+                name: old.name,
+            });
+        }
+    }
+    others.extend(deleted);
+    others
+}
+
+/// Merges states deleted from one file and created in another into "Moved" rows, near identical
+/// ones only if there are few enough left to compare.
+/// Files left without any rows because all of their changes moved elsewhere are marked "MOVED".
+pub fn pair_moves(files: &mut BTreeMap<&str, IconFileDiff>) {
+    let states_with = |status: &'static str| {
+        files
+            .iter()
+            .flat_map(move |(path, diff)| {
+                diff.states
+                    .iter()
+                    .enumerate()
+                    .filter(move |(_, state)| state.status == status)
+                    .filter_map(move |(index, state)| {
+                        Some((*path, index, state.fingerprint.as_ref()?))
+                    })
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut deleted = states_with("Deleted");
+    let mut created = states_with("Created");
+
+    let mut moves = Vec::new();
+    for exact in [true, false] {
+        if !exact && !near_pass_allowed(created.len(), deleted.len()) {
+            break;
+        }
+        created.retain(|&(path, index, fingerprint)| {
+            let Some(position) = deleted
+                .iter()
+                .position(|&(from, _, old)| from != path && old.matches(fingerprint, exact))
+            else {
+                return true;
+            };
+            let (from, from_index, _) = deleted.remove(position);
+            moves.push(((path, index), (from, from_index), exact));
+            false
+        });
+    }
+
+    let mut removed: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for ((path, index), (from, from_index), exact) in moves {
+        let old_state = &files[from].states[from_index];
+        let old = OldState {
+            file: Some(from.to_owned()),
+            duplicate: old_state.duplicate,
+            name: old_state.name.clone(),
+        };
+        removed.entry(from).or_default().push(from_index);
+
+        let state = &mut files.get_mut(path).unwrap().states[index];
+        state.status = "Moved";
+        state.changes.push(format!(
+            "Moved from `{from}`, was `{} ({})`{}",
+            old.name,
+            old.duplicate,
+            pairing_note(exact)
+        ));
+        state.old_state = Some(old);
+    }
+
+    for (path, indices) in removed {
+        let diff = files.get_mut(path).unwrap();
+        let mut index = 0;
+        diff.states.retain(|_| {
+            let keep = !indices.contains(&index);
+            index += 1;
+            keep
+        });
+        if diff.states.is_empty() && diff.changes.is_empty() {
+            diff.status = "MOVED";
+        }
+    }
+
+    for diff in files.values_mut() {
+        for state in diff.states.iter_mut() {
+            // The renders are only needed for pairing
+            state.fingerprint = None;
+        }
+        sort_states(&mut diff.states);
+    }
+}
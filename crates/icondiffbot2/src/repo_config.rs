@@ -0,0 +1,99 @@
+use diffbot_lib::{
+    job::types::Job,
+    metrics,
+    repo_config::{merge_configs, MergedConfig, PathFilter},
+};
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+pub const REPO_CONFIG_PATH: &str = ".github/icondiffbot.toml";
+const MAX_UPSCALE: u32 = 8;
+
+/// Per-repository settings, read from the base branch
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Top level keys the PR's own config may override
+    pub pr_overridable: Vec<String>,
+    /// Only icons matching one of these globs are rendered, everything if empty
+    pub include: Vec<String>,
+    /// Icons matching one of these globs are never rendered
+    pub exclude: Vec<String>,
+    /// Maximum amount of state rows shown per icon file
+    pub max_states: Option<usize>,
+    /// Integer factor previews are pre-upscaled by with nearest neighbour scaling
+    pub upscale: u32,
+    /// Show states of modified icons that did not change
+    pub show_unchanged: bool,
+    /// Overrides the server's summary message
+    pub summary_msg: Option<String>,
+}
+
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            pr_overridable: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_states: None,
+            upscale: 1,
+            show_unchanged: false,
+            summary_msg: None,
+        }
+    }
+}
+
+impl RepoConfig {
+    /// Fetches the config off the base commit, applying the overrides it allows from the head commit
+    ///
+    /// Also returns a notice for the check summary if the PR modifies the config
+    pub async fn fetch(job: &Job) -> Result<(Self, Option<String>)> {
+        let base = fetch_file(job, &job.base.sha).await?;
+        let head = fetch_file(job, &job.head.sha).await?;
+
+        let merged = merge_configs(base.as_deref(), head.as_deref())?;
+        Ok((Self::from_merged(&merged)?, merged.notice(REPO_CONFIG_PATH)))
+    }
+
+    pub fn from_merged(merged: &MergedConfig) -> Result<Self> {
+        let config: Self = merged
+            .deserialize()
+            .wrap_err_with(|| format!("Parsing {REPO_CONFIG_PATH}"))?;
+        if !(1..=MAX_UPSCALE).contains(&config.upscale) {
+            return Err(eyre::eyre!("upscale has to be between 1 and {MAX_UPSCALE}"));
+        }
+        config.file_filter()?;
+        Ok(config)
+    }
+
+    pub fn file_filter(&self) -> Result<PathFilter> {
+        PathFilter::new(&self.include, &self.exclude)
+    }
+}
+
+/// Returns the config file's contents at the given commit, if there is one
+async fn fetch_file(job: &Job, sha: &str) -> Result<Option<String>> {
+    let (owner, repo) = job.repo.name_tuple();
+    metrics::github_api_call("get_repo_config");
+    let items = match octocrab::instance()
+        .installation(job.installation)?
+        .repos(owner, repo)
+        .get_content()
+        .path(REPO_CONFIG_PATH)
+        .r#ref(sha)
+        .send()
+        .await
+    {
+        Ok(mut content) => content.take_items(),
+        Err(octocrab::Error::GitHub { source, .. }) if source.status_code == 404 => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).wrap_err("Fetching repository config"),
+    };
+
+    items
+        .first()
+        .and_then(|item| item.decoded_content())
+        .map(Some)
+        .ok_or_else(|| eyre::eyre!("{REPO_CONFIG_PATH} is not a file"))
+}
@@ -1,9 +1,13 @@
-use std::time::Duration;
-
 use super::job_processor::do_job;
 use diffbot_lib::job::types::Job;
 
-use diffbot_lib::tracing;
+use diffbot_lib::{
+    job::{
+        shutdown,
+        tracker::{self, JobResult},
+    },
+    metrics, tracing,
+};
 
 pub async fn handle_jobs<S: AsRef<str>>(
     name: S,
@@ -11,7 +15,12 @@ pub async fn handle_jobs<S: AsRef<str>>(
     client: reqwest::Client,
 ) {
     loop {
-        match job_receiver.recv_async().await {
+        let received = job_receiver.recv_async().await;
+        if shutdown::is_shutting_down() {
+            tracing::info!("Shutting down, leaving the queue to the checkpoint");
+            return;
+        }
+        match received {
             Ok(job) => {
                 tracing::info!("Job received from queue");
                 job_handler(name.as_ref(), job, client.clone()).await;
@@ -30,14 +39,37 @@ async fn job_handler(name: &str, job: Job, client: reqwest::Client) {
         check_run.id()
     );
 
+    let Some((cancel, token)) = tracker::started(&job) else {
+        tracing::info!(
+            "[{}#{pull_request}] [{}] Cancelled while queued, skipping",
+            repo.full_name(),
+            check_run.id()
+        );
+        return;
+    };
+
     _ = check_run.mark_started().await;
+    let installation = job.installation;
+    let timeout = crate::read_config().job_timeout(&repo.full_name());
 
-    let output = actix_web::rt::time::timeout(
-        Duration::from_secs(7200),
-        actix_web::rt::task::spawn_blocking(move || do_job(job, client)),
+    let output = tracker::until_cancelled(
+        cancel,
+        actix_web::rt::time::timeout(
+            timeout,
+            actix_web::rt::task::spawn_blocking(move || do_job(job, client, token)),
+        ),
     )
     .await;
 
+    let Some(output) = output else {
+        tracing::info!(
+            "[{}#{pull_request}] [{}] Cancelled",
+            repo.full_name(),
+            check_run.id()
+        );
+        return;
+    };
+
     tracing::info!(
         "[{}#{pull_request}] [{}] Finished",
         repo.full_name(),
@@ -46,8 +78,9 @@ async fn job_handler(name: &str, job: Job, client: reqwest::Client) {
 
     let output = {
         if output.is_err() {
-            tracing::error!("Job timed out!");
-            _ = check_run.mark_failed("Job timed out after 1 hours!").await;
+            let message = tracker::timed_out(check_run.id(), timeout);
+            tracing::error!("{message}");
+            _ = check_run.mark_failed(&message).await;
             return;
         }
         output.unwrap()
@@ -62,6 +95,7 @@ async fn job_handler(name: &str, job: Job, client: reqwest::Client) {
         };
         tracing::error!("Join Handle error: {fuckup}");
         _ = check_run.mark_failed(&fuckup).await;
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
         return;
     }
 
@@ -70,15 +104,24 @@ async fn job_handler(name: &str, job: Job, client: reqwest::Client) {
         let fuckup = format!("{e:?}");
         tracing::error!("Other rendering error: {fuckup}");
         _ = check_run.mark_failed(&fuckup).await;
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
         return;
     }
 
     let output = output.unwrap();
-    if let Err(e) = diffbot_lib::job::runner::handle_output(output, &check_run, name).await {
+    let timer = tracker::enter_stage(check_run.id(), "github_output");
+    let result = diffbot_lib::job::runner::handle_output(output, &check_run, name).await;
+    drop(timer);
+    if let Err(e) = result {
         let fuckup = format!("{e:?}");
         tracing::error!("Output upload error: {fuckup}");
         _ = check_run
             .mark_failed(&format!("Failed to upload job output: {fuckup}"))
             .await;
-    };
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
+    } else {
+        tracker::finished(check_run.id(), JobResult::Succeeded);
+    }
+
+    metrics::update_rate_limit(installation).await;
 }
@@ -0,0 +1,100 @@
+use eyre::{Context, Result};
+use image::{imageops::FilterType, ImageFormat, RgbaImage};
+use std::io::Cursor;
+
+/// Nearest neighbour upscale by an integer factor, keeps sprites crisp
+pub fn upscale_image(image: &RgbaImage, factor: u32) -> RgbaImage {
+    if factor <= 1 {
+        return image.clone();
+    }
+    image::imageops::resize(
+        image,
+        image.width() * factor,
+        image.height() * factor,
+        FilterType::Nearest,
+    )
+}
+
+/// Upscales an already encoded state render, keeping its format
+pub fn upscale_encoded(bytes: &[u8], format: ImageFormat, factor: u32) -> Result<Vec<u8>> {
+    match format {
+        ImageFormat::Gif => upscale_gif(bytes, factor),
+        _ => upscale_png(bytes, factor),
+    }
+}
+
+fn upscale_png(bytes: &[u8], factor: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory_with_format(bytes, ImageFormat::Png)
+        .wrap_err("Decoding png")?
+        .into_rgba8();
+
+    let mut out = Vec::new();
+    upscale_image(&image, factor)
+        .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+        .wrap_err("Encoding upscaled png")?;
+    Ok(out)
+}
+
+/// Scales the palette indices of every frame, so the colours, delays, disposal and the loop
+/// count the state's `loop_flag` was rendered with are kept as they are
+fn upscale_gif(bytes: &[u8], factor: u32) -> Result<Vec<u8>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(Cursor::new(bytes))
+        .wrap_err("Decoding gif")?;
+
+    let scale =
+        |size: u16| u16::try_from(u32::from(size) * factor).wrap_err("Upscaled gif is too large");
+    let (width, height) = (scale(decoder.width())?, scale(decoder.height())?);
+    let global_palette = decoder.global_palette().unwrap_or_default().to_vec();
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().wrap_err("Decoding gif frame")? {
+        frames.push(gif::Frame {
+            width: scale(frame.width)?,
+            height: scale(frame.height)?,
+            left: scale(frame.left)?,
+            top: scale(frame.top)?,
+            // The decoder already put interlaced rows in order
+            interlaced: false,
+            buffer: duplicate_pixels(&frame.buffer, frame.width.into(), factor as usize).into(),
+            ..frame.clone()
+        });
+    }
+    // Only known once the extension blocks before the frames were read
+    let repeat = decoder.repeat();
+
+    let mut out = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut out, width, height, &global_palette)
+            .wrap_err("Encoding upscaled gif")?;
+        // A finite count of zero is what the decoder reports without a loop extension, while
+        // writing one would make the gif loop forever
+        if !matches!(repeat, gif::Repeat::Finite(0)) {
+            encoder.set_repeat(repeat).wrap_err("Setting gif repeat")?;
+        }
+        for frame in frames {
+            encoder
+                .write_frame(&frame)
+                .wrap_err("Encoding upscaled gif frame")?;
+        }
+    }
+    Ok(out)
+}
+
+/// Nearest neighbour upscale of a row major buffer with one byte per pixel
+fn duplicate_pixels(buffer: &[u8], width: usize, factor: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buffer.len() * factor * factor);
+    for row in buffer.chunks(width.max(1)) {
+        let start = out.len();
+        out.extend(
+            row.iter()
+                .flat_map(|&pixel| std::iter::repeat(pixel).take(factor)),
+        );
+        for _ in 1..factor {
+            out.extend_from_within(start..start + row.len() * factor);
+        }
+    }
+    out
+}
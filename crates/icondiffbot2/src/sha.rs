@@ -1,13 +1,20 @@
-use actix_web::rt::Runtime;
-use diffbot_lib::{github::github_types::ChangeType, job::types::Job};
+use diffbot_lib::{
+    github::{
+        github_types::{ChangeType, FileDiff},
+        graphql::{get_blobs, BlobInfo},
+    },
+    job::types::Job,
+    metrics,
+};
 use dmm_tools::dmi::IconFile;
 use eyre::{Context, Result};
+use futures::stream::{self, StreamExt};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     hash::{Hash, Hasher},
 };
 
-use crate::downloading::download_url;
+use crate::downloading::download_blob;
 
 #[derive(Debug)]
 pub struct IconFileWithName {
@@ -17,6 +24,12 @@ pub struct IconFileWithName {
     pub icon: IconFile,
 }
 
+/// Before and after version of a changed icon file, `None` if it doesn't exist on that side
+pub type IconFilePair = (
+    Result<Option<IconFileWithName>>,
+    Result<Option<IconFileWithName>>,
+);
+
 pub fn status_to_sha<'a>(job: &'a Job, status: &ChangeType) -> (Option<&'a str>, Option<&'a str>) {
     match status {
         ChangeType::Added => (None, Some(&job.head.sha)),
@@ -26,42 +39,109 @@ pub fn status_to_sha<'a>(job: &'a Job, status: &ChangeType) -> (Option<&'a str>,
     }
 }
 
-pub fn sha_to_iconfile(
+/// Downloads both versions of every file, at most `concurrency` blobs at a time
+///
+/// Files are looked up by their blob sha, so blobs shared between files or already in the
+/// disk cache are only fetched once. Blobs over `max_size` bytes are never downloaded and
+/// result in an error for that file only. Results are in the same order as `files`.
+pub async fn load_icon_files(
     job: &Job,
-    filename: &str,
-    sha: (Option<&str>, Option<&str>),
-    client: reqwest::Client,
-) -> Result<(Result<Option<IconFileWithName>>, Option<IconFileWithName>)> {
-    Ok((
-        get_if_exists(job, filename, sha.0, client.clone()),
-        get_if_exists(job, filename, sha.1, client)?,
-    ))
-}
+    files: &[&FileDiff],
+    client: &reqwest::Client,
+    (concurrency, max_size): (usize, u64),
+) -> Result<Vec<IconFilePair>> {
+    let (mut base_paths, mut head_paths) = (Vec::new(), Vec::new());
+    for file in files {
+        let (base_sha, head_sha) = status_to_sha(job, &file.status);
+        if base_sha.is_some() {
+            base_paths.push(file.filename.as_str());
+        }
+        if head_sha.is_some() {
+            head_paths.push(file.filename.as_str());
+        }
+    }
 
-#[tracing::instrument]
-fn get_if_exists(
-    job: &Job,
-    filename: &str,
-    sha: Option<&str>,
-    client: reqwest::Client,
-) -> Result<Option<IconFileWithName>> {
-    let Some(sha) = sha else { return Ok(None) };
-    let rt = Runtime::new()?;
-    let raw = rt.block_on(async {
-        download_url(&job.installation, &job.repo, filename, sha, client)
-            .await
-            .wrap_err_with(|| format!("Failed to download file {filename:?}"))
-    })?;
+    let base_blobs = get_blobs(
+        job.repo.name_tuple(),
+        job.installation,
+        &job.base.sha,
+        &base_paths,
+    )
+    .await
+    .wrap_err("Resolving base blobs")?;
+    let head_blobs = get_blobs(
+        job.repo.name_tuple(),
+        job.installation,
+        &job.head.sha,
+        &head_paths,
+    )
+    .await
+    .wrap_err("Resolving head blobs")?;
+
+    let unique_blobs: HashSet<&str> = base_blobs
+        .values()
+        .chain(head_blobs.values())
+        .filter(|blob| blob.size <= max_size)
+        .map(|blob| blob.sha.as_str())
+        .collect();
+
+    let downloaded: HashMap<&str, Result<Vec<u8>, String>> = stream::iter(unique_blobs)
+        .map(|blob_sha| async move {
+            let bytes = download_blob(&job.installation, &job.repo, blob_sha, client, max_size)
+                .await
+                .map_err(|e| format!("{e:?}"));
+            (blob_sha, bytes)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let load = |file: &FileDiff,
+                sha: Option<&str>,
+                blobs: &HashMap<String, BlobInfo>|
+     -> Result<Option<IconFileWithName>> {
+        let Some(sha) = sha else { return Ok(None) };
+        let blob = blobs
+            .get(&file.filename)
+            .ok_or_else(|| eyre::eyre!("{:?} does not exist at {sha}", file.filename))?;
+        if blob.size > max_size {
+            return Err(eyre::eyre!(
+                "{:?} is {} bytes, over the limit of {max_size} bytes",
+                file.filename,
+                blob.size
+            ));
+        }
+        let raw = downloaded[blob.sha.as_str()]
+            .as_ref()
+            .map_err(|e| eyre::eyre!("{e}"))
+            .wrap_err_with(|| format!("Failed to download file {:?}", file.filename))?;
+        parse_icon(&file.filename, sha, raw).map(Some)
+    };
+
+    Ok(files
+        .iter()
+        .map(|file| {
+            let (base_sha, head_sha) = status_to_sha(job, &file.status);
+            (
+                load(file, base_sha, &base_blobs),
+                load(file, head_sha, &head_blobs),
+            )
+        })
+        .collect())
+}
 
+#[tracing::instrument(skip(raw))]
+fn parse_icon(filename: &str, sha: &str, raw: &[u8]) -> Result<IconFileWithName> {
+    let _timer = metrics::time_stage("parse");
     let mut hasher = DefaultHasher::new();
     raw.hash(&mut hasher);
     let hash = hasher.finish();
 
-    Ok(Some(IconFileWithName {
+    Ok(IconFileWithName {
         full_name: filename.to_string(),
         sha: sha.to_string(),
         hash,
-        icon: IconFile::from_bytes(&raw)
+        icon: IconFile::from_bytes(raw)
             .wrap_err_with(|| format!("IconFile::from_bytes failed for {filename:?}"))?,
-    }))
+    })
 }
@@ -1,10 +1,254 @@
-use diffbot_lib::github::github_types::{CheckOutputs, Output};
+use crate::renames::{pair_moves, Fingerprint};
+use diffbot_lib::github::github_types::{CheckOutputs, Conclusion, Output};
 use eyre::Result;
-use std::collections::HashMap;
+use image::RgbaImage;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Url of a rendered state, plus the original size render if the preview was upscaled
+#[derive(Serialize, Debug)]
+pub struct RenderedImage {
+    pub url: String,
+    pub original: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StateDiff {
+    pub name: String,
+    pub duplicate: usize,
+    pub status: &'static str,
+    pub old: Option<RenderedImage>,
+    pub new: Option<RenderedImage>,
+    /// Highlight of the changed pixels, only set for modified states
+    pub diff: Option<String>,
+    /// Metadata differences, only set for modified states
+    pub changes: Vec<String>,
+    /// Set on created and deleted states, used to find renamed and moved ones
+    #[serde(skip)]
+    pub fingerprint: Option<Fingerprint>,
+    /// Set on renamed and moved states, the state the old image is rendered from
+    #[serde(skip)]
+    pub old_state: Option<OldState>,
+    /// Set on modified states until the highlight is written
+    #[serde(skip)]
+    pub highlight: Option<RgbaImage>,
+}
+
+/// A state of an old icon file version
+#[derive(Debug)]
+pub struct OldState {
+    /// Path of the file, `None` if it is the same file the row belongs to
+    pub file: Option<String>,
+    pub duplicate: usize,
+    pub name: String,
+}
+
+impl StateDiff {
+    /// A row without images, they are rendered once it is known which rows are shown
+    pub fn new(name: String, duplicate: usize, status: &'static str) -> Self {
+        Self {
+            name,
+            duplicate,
+            status,
+            old: None,
+            new: None,
+            diff: None,
+            changes: Vec::new(),
+            fingerprint: None,
+            old_state: None,
+            highlight: None,
+        }
+    }
+
+    fn to_row(&self) -> String {
+        format!(
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/templates/diff_line.txt"
+            )),
+            state_name = format_args!("{} ({})", self.name, self.duplicate),
+            old = image_cell(self.old.as_ref()),
+            new = image_cell(self.new.as_ref()),
+            diff = self
+                .diff
+                .as_deref()
+                .map(|url| format!("![{url}]({url})"))
+                .unwrap_or_default(),
+            changes = self.changes.join("<br>"),
+            change_text = self.status,
+        )
+    }
+}
+
+/// Order states are grouped in, anything unknown goes last
+const STATUS_ORDER: &[&str] = &[
+    "Modified",
+    "Renamed",
+    "Moved",
+    "Created",
+    "Deleted",
+    "Unchanged",
+];
+
+/// Sorts states by status, then name, then duplicate index
+pub fn sort_states(states: &mut [StateDiff]) {
+    let status_index = |status: &str| {
+        STATUS_ORDER
+            .iter()
+            .position(|order| *order == status)
+            .unwrap_or(STATUS_ORDER.len())
+    };
+    states.sort_by(|a, b| {
+        (status_index(a.status), &a.name, a.duplicate).cmp(&(
+            status_index(b.status),
+            &b.name,
+            b.duplicate,
+        ))
+    });
+}
+
+fn image_cell(image: Option<&RenderedImage>) -> String {
+    match image {
+        Some(RenderedImage {
+            url,
+            original: Some(original),
+        }) => format!("![{url}]({url}) [original]({original})"),
+        Some(RenderedImage {
+            url,
+            original: None,
+        }) => format!("![{url}]({url})"),
+        None => "![]()".to_owned(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct IconFileDiff {
+    pub status: &'static str,
+    pub states: Vec<StateDiff>,
+    pub omitted_states: usize,
+    /// Metadata differences that apply to the whole file
+    pub changes: Vec<String>,
+    pub error: Option<String>,
+}
+
+/// Keeps multi-line text such as error chains inside a single markdown table cell
+fn table_cell(text: &str) -> String {
+    text.trim_end()
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+impl IconFileDiff {
+    pub fn new(status: &'static str, mut states: Vec<StateDiff>) -> Self {
+        sort_states(&mut states);
+        Self {
+            status,
+            states,
+            omitted_states: 0,
+            changes: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn with_changes(mut self, changes: Vec<String>) -> Self {
+        self.changes = changes;
+        self
+    }
+
+    /// Drops every state past `max_states`, keeping count of how many were dropped
+    pub fn truncate(&mut self, max_states: Option<usize>) {
+        if let Some(max_states) = max_states {
+            if self.states.len() > max_states {
+                self.omitted_states = self.states.len() - max_states;
+                self.states.truncate(max_states);
+            }
+        }
+    }
+
+    pub fn unchanged() -> Self {
+        Self::new("UNCHANGED", Vec::new())
+    }
+
+    pub fn error(error: String) -> Self {
+        Self {
+            status: "ERROR",
+            states: Vec::new(),
+            omitted_states: 0,
+            changes: Vec::new(),
+            error: Some(error),
+        }
+    }
+
+    fn rows(&self) -> Vec<String> {
+        if let Some(ref error) = self.error {
+            return vec![format!(
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/templates/diff_line_error.txt"
+                )),
+                error = table_cell(error),
+            )];
+        }
+        if self.status == "UNCHANGED" || self.status == "MOVED" {
+            return vec![format!(
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/templates/diff_line.txt"
+                )),
+                state_name = "",
+                old = "",
+                new = "",
+                diff = "",
+                changes = "",
+                change_text = self.status,
+            )];
+        }
+        let mut rows = Vec::new();
+        if !self.changes.is_empty() {
+            rows.push(format!(
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/templates/diff_line.txt"
+                )),
+                state_name = "File metadata",
+                old = "",
+                new = "",
+                diff = "",
+                changes = self.changes.join("<br>"),
+                change_text = "Modified",
+            ));
+        }
+        rows.extend(self.states.iter().map(|state| state.to_row()));
+        if self.omitted_states > 0 {
+            rows.push(format!(
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/templates/diff_line.txt"
+                )),
+                state_name = format_args!("{} more states", self.omitted_states),
+                old = "",
+                new = "",
+                diff = "",
+                changes = "",
+                change_text = "Omitted",
+            ));
+        }
+        rows
+    }
+}
+
+/// A single file entry of the json report
+#[derive(Serialize, Debug)]
+pub struct IconFileReport<'a> {
+    pub path: &'a str,
+    #[serde(flatten)]
+    pub diff: &'a IconFileDiff,
+}
 
 #[derive(Default, Debug)]
 pub struct OutputTableBuilder<'a> {
-    map: HashMap<&'a str, (&'static str, Vec<String>)>,
+    map: BTreeMap<&'a str, IconFileDiff>,
 }
 
 impl<'a> OutputTableBuilder<'a> {
@@ -13,30 +257,56 @@ impl<'a> OutputTableBuilder<'a> {
     }
 
     #[tracing::instrument]
-    pub fn insert(
-        &mut self,
-        k: &'a str,
-        v: (&'static str, Vec<String>),
-    ) -> Option<(&'static str, Vec<String>)> {
+    pub fn insert(&mut self, k: &'a str, v: IconFileDiff) -> Option<IconFileDiff> {
         self.map.insert(k, v)
     }
 
+    pub fn get_mut(&mut self, k: &str) -> Option<&mut IconFileDiff> {
+        self.map.get_mut(k)
+    }
+
+    /// Turns states deleted from one file and created in another into moves
+    pub fn pair_moves(&mut self) {
+        pair_moves(&mut self.map);
+    }
+
+    /// Drops the states past `max_states` of every file, after they were paired
+    pub fn truncate(&mut self, max_states: Option<usize>) {
+        for diff in self.map.values_mut() {
+            diff.truncate(max_states);
+        }
+    }
+
+    /// Amount of files that got an error row instead of a diff
+    pub fn failed_files(&self) -> usize {
+        self.map
+            .values()
+            .filter(|diff| diff.error.is_some())
+            .count()
+    }
+
+    pub fn reports(&self) -> impl Iterator<Item = IconFileReport<'_>> {
+        self.map
+            .iter()
+            .map(|(path, diff)| IconFileReport { path, diff })
+    }
+
     #[tracing::instrument]
-    pub fn build(&self) -> Result<CheckOutputs> {
+    pub fn build(&self, summary: &str) -> Result<CheckOutputs> {
         // TODO: Make this not shit
         let mut file_names: HashMap<&str, u32> = HashMap::new();
         let mut details: Vec<(String, &str, String)> = Vec::new();
         let mut current_table = String::new();
 
-        for (file_name, (change_type, states)) in self.map.iter() {
+        for (file_name, diff) in self.map.iter() {
             let entry = file_names.entry(file_name).or_insert(0);
 
-            for state in states {
+            for state in diff.rows() {
                 // A little extra buffer room for the <detail> block
                 if current_table.len() + state.len() > 55_000 {
                     details.push((
                         format!("{file_name} ({})", *entry),
-                        change_type,
+                        diff.status,
                         std::mem::take(&mut current_table),
                     ));
                     *entry += 1;
@@ -48,7 +318,7 @@ impl<'a> OutputTableBuilder<'a> {
             if !current_table.is_empty() {
                 details.push((
                     format!("{file_name} ({})", *entry),
-                    change_type,
+                    diff.status,
                     std::mem::take(&mut current_table),
                 ));
                 *entry += 1;
@@ -59,8 +329,6 @@ impl<'a> OutputTableBuilder<'a> {
         let mut current_output_text = String::new();
 
         for (file_name, change_type, table) in details.iter() {
-            // TODO: use an <img> tag so i can set a style that upscales 32x32 to 64x64
-            // and sets all the browser flags for nearest neighbor scaling
             let diff_block = format!(
                 include_str!(concat!(
                     env!("CARGO_MANIFEST_DIR"),
@@ -74,7 +342,7 @@ impl<'a> OutputTableBuilder<'a> {
             if current_output_text.len() + diff_block.len() > 60_000 {
                 chunks.push(Output {
                     title: "Icon difference rendering",
-                    summary: crate::read_config().summary_msg.to_string(),
+                    summary: summary.to_owned(),
                     text: std::mem::take(&mut current_output_text),
                 });
             }
@@ -85,10 +353,17 @@ impl<'a> OutputTableBuilder<'a> {
         if !current_output_text.is_empty() {
             chunks.push(Output {
                 title: "Icon difference rendering",
-                summary: crate::read_config().summary_msg.to_string(),
+                summary: summary.to_owned(),
                 text: std::mem::take(&mut current_output_text),
             });
         }
-        Ok(chunks)
+        Ok(CheckOutputs {
+            outputs: chunks,
+            conclusion: if self.failed_files() > 0 {
+                Conclusion::Neutral
+            } else {
+                Conclusion::Success
+            },
+        })
     }
 }
@@ -5,31 +5,83 @@ use octocrab::models::InstallationId;
 use crate::DataJobSender;
 use diffbot_lib::{
     github::{
-        github_api::CheckRun,
+        commands::parse_commands,
+        github_api::{get_pull_request, has_write_access, CheckRun},
         github_types::{
-            ChangeType, Installation, Output, PullRequest, PullRequestEventPayload, Repository,
+            ChangeType, Installation, IssueCommentEventPayload, Output, PullRequest,
+            PullRequestEventPayload, Repository,
         },
         graphql::get_pull_files,
+        installation::{is_installation_event, parse_installation_event},
+        webhook,
+    },
+    job::{
+        limits::limit_files,
+        reconcile, tracker,
+        types::{Job, JobOptions, JobType},
     },
-    job::types::{Job, JobType},
     tracing,
 };
 
+/// What a `/mdb` comment asked for
+struct CommandRequest {
+    options: JobOptions,
+    only_file: Option<String>,
+}
+
+fn parse_command(args: &[&str]) -> Option<CommandRequest> {
+    match args {
+        ["rerun"] => Some(CommandRequest {
+            options: Default::default(),
+            only_file: None,
+        }),
+        ["render", file, rest @ ..] => {
+            let z_level = match rest {
+                [] => None,
+                [z] => Some(z.strip_prefix("z=")?.parse().ok()?),
+                _ => return None,
+            };
+            Some(CommandRequest {
+                options: JobOptions {
+                    full_render: true,
+                    all_z_levels: false,
+                    z_level,
+                },
+                only_file: Some(file.to_string()),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn label_options(pull: &PullRequest) -> JobOptions {
+    let labels = &crate::read_config().labels;
+    let has = |label: &Option<String>| label.as_deref().is_some_and(|name| pull.has_label(name));
+    JobOptions {
+        full_render: has(&labels.full_render),
+        all_z_levels: has(&labels.all_z_levels),
+        z_level: None,
+    }
+}
+
 async fn process_pull(
     repo: Repository,
     pull: PullRequest,
     check_run: CheckRun,
     installation: &Installation,
     job_sender: DataJobSender,
+    request: Option<CommandRequest>,
 ) -> Result<()> {
     tracing::debug!("Processing pull request");
 
-    if pull
-        .title
-        .as_ref()
-        .ok_or_else(|| eyre::anyhow!("PR title is None"))?
-        .to_ascii_lowercase()
-        .contains("[mdb ignore]")
+    // Explicitly asking for a render through a comment overrides the skip markers
+    if request.is_none()
+        && pull
+            .title
+            .as_ref()
+            .ok_or_else(|| eyre::anyhow!("PR title is None"))?
+            .to_ascii_lowercase()
+            .contains("[mdb ignore]")
     {
         let output = Output {
             title: "PR Ignored",
@@ -42,19 +94,15 @@ async fn process_pull(
         return Ok(());
     }
 
-    let (blacklist, contact) = {
-        let conf = &crate::CONFIG.get().unwrap();
-        (&conf.blacklist, &conf.blacklist_contact)
-    };
-
-    if blacklist.contains(&repo.id) {
+    if let Some(skip_label) = crate::read_config()
+        .labels
+        .skip
+        .as_deref()
+        .filter(|label| request.is_none() && pull.has_label(label))
+    {
         let output = Output {
-            title: "Repo blacklisted",
-            summary: format!(
-                "Repository {} is blacklisted. {}",
-                repo.full_name(),
-                contact
-            ),
+            title: "PR Ignored",
+            summary: format!("This PR has the `{skip_label}` label. Aborting."),
             text: "".to_owned(),
         };
 
@@ -63,13 +111,29 @@ async fn process_pull(
         return Ok(());
     }
 
-    let files = match get_pull_files(repo.name_tuple(), installation.id, &pull)
+    let conf = crate::read_config();
+    if let Err(denied) = conf.policy.check(
+        installation.id,
+        &repo,
+        (&conf.blacklist, &conf.blacklist_contact),
+    ) {
+        check_run.mark_skipped(denied.into_output()).await?;
+
+        return Ok(());
+    }
+
+    let only_file = request
+        .as_ref()
+        .and_then(|request| request.only_file.as_deref());
+
+    let mut files = match get_pull_files(repo.name_tuple(), installation.id, &pull)
         .await
         .wrap_err("Getting files modified by PR")
     {
         Ok(files) => files
             .into_iter()
             .filter(|f| f.filename.ends_with(".dmm"))
+            .filter(|f| only_file.is_none_or(|only_file| f.filename == only_file))
             .filter(|f| {
                 matches!(
                     f.status,
@@ -86,7 +150,10 @@ async fn process_pull(
     if files.is_empty() {
         let output = Output {
             title: "No map changes",
-            summary: "There are no relevant changed map files to render.".to_owned(),
+            summary: match only_file {
+                Some(only_file) => format!("`{only_file}` is not a map changed by this PR."),
+                None => "There are no relevant changed map files to render.".to_owned(),
+            },
             text: "".to_owned(),
         };
 
@@ -95,8 +162,21 @@ async fn process_pull(
         return Ok(());
     }
 
+    let truncated = limit_files(
+        &mut files,
+        crate::read_config().limits(installation.id).max_files,
+        "maps",
+    )
+    .into_iter()
+    .collect();
+
     check_run.mark_queued().await?;
 
+    let options = match request {
+        Some(request) => request.options,
+        None => label_options(&pull),
+    };
+
     let job = Job {
         repo,
         base: pull.base,
@@ -105,8 +185,11 @@ async fn process_pull(
         files,
         check_run,
         installation: InstallationId(installation.id),
+        options,
+        truncated,
     };
 
+    tracker::queued(&job);
     job_sender
         .send_async(JobType::GithubJob(Box::new(job)))
         .await?;
@@ -116,6 +199,81 @@ async fn process_pull(
     Ok(())
 }
 
+async fn submit_check(
+    repository: Repository,
+    pull_request: PullRequest,
+    installation: &Installation,
+    job_sender: DataJobSender,
+    pool: &Option<mysql_async::Pool>,
+    request: Option<CommandRequest>,
+) -> Result<&'static str> {
+    tracing::debug!("Creating checkrun");
+
+    let check_run = CheckRun::create(
+        &repository.full_name(),
+        &pull_request.head.sha,
+        installation.id,
+        Some("MapDiffBot2"),
+    )
+    .await?;
+
+    let (check_id, repo_id, pr_number) = (check_run.id(), repository.id, pull_request.number);
+
+    process_pull(
+        repository,
+        pull_request,
+        check_run,
+        installation,
+        job_sender,
+        request,
+    )
+    .await?;
+
+    if let Some(ref pool) = pool {
+        let mut conn = match pool.get_conn().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                return Ok("Getting mysql connection failed");
+            }
+        };
+        if let Err(e) = conn
+            .exec_drop(
+                r"INSERT INTO jobs (
+                check_id,
+                repo_id,
+                pr_number,
+                merge_date,
+                retention_days,
+                installation_id
+            )
+            VALUES(
+                :check_id,
+                :repo_id,
+                :pr_number,
+                :merge_date,
+                :retention_days,
+                :installation_id
+            )
+            ",
+                params! {
+                    "check_id" => check_id,
+                    "repo_id" => repo_id,
+                    "pr_number" => pr_number,
+                    "merge_date" => None::<usize>,
+                    "retention_days" => crate::read_config().policy.retention_days(installation.id),
+                    "installation_id" => installation.id,
+                },
+            )
+            .await
+        {
+            tracing::error!("{:?}", e);
+        };
+    }
+
+    Ok("Check submitted")
+}
+
 async fn handle_pull_request(
     payload: String,
     job_sender: DataJobSender,
@@ -127,66 +285,34 @@ async fn handle_pull_request(
 
     match payload.action.as_str() {
         "opened" | "synchronize" => {
-            tracing::debug!("Creating checkrun");
-
-            let check_run = CheckRun::create(
-                &payload.repository.full_name(),
-                &payload.pull_request.head.sha,
-                payload.installation.id,
-                Some("MapDiffBot2"),
-            )
-            .await?;
-
-            let (check_id, repo_id, pr_number) = (
-                check_run.id(),
-                payload.repository.id,
-                payload.pull_request.number,
-            );
-
-            process_pull(
+            submit_check(
                 payload.repository,
                 payload.pull_request,
-                check_run,
                 &payload.installation,
                 job_sender,
+                pool,
+                None,
             )
-            .await?;
-
-            if let Some(ref pool) = pool {
-                let mut conn = match pool.get_conn().await {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        tracing::error!("{:?}", e);
-                        return Ok("Getting mysql connection failed");
-                    }
-                };
-                if let Err(e) = conn
-                    .exec_drop(
-                        r"INSERT INTO jobs (
-                        check_id,
-                        repo_id,
-                        pr_number,
-                        merge_date
-                    )
-                    VALUES(
-                        :check_id,
-                        :repo_id,
-                        :pr_number,
-                        :merge_date
-                    )
-                    ",
-                        params! {
-                            "check_id" => check_id,
-                            "repo_id" => repo_id,
-                            "pr_number" => pr_number,
-                            "merge_date" => None::<usize>,
-                        },
-                    )
-                    .await
-                {
-                    tracing::error!("{:?}", e);
-                };
+            .await
+        }
+        "labeled" | "unlabeled" => {
+            let labels = &crate::read_config().labels;
+            if !payload
+                .label
+                .as_ref()
+                .is_some_and(|label| labels.is_relevant(&label.name))
+            {
+                return Ok("Label not relevant");
             }
+            submit_check(
+                payload.repository,
+                payload.pull_request,
+                &payload.installation,
+                job_sender,
+                pool,
+                None,
+            )
+            .await
         }
         "closed" => {
             if let Some(ref pool) = pool {
@@ -216,11 +342,180 @@ async fn handle_pull_request(
                     tracing::error!("{:?}", e);
                 };
             }
+            Ok("Check submitted")
         }
-        _ => return Ok("PR not opened or updated"),
+        _ => Ok("PR not opened or updated"),
     }
+}
 
-    Ok("Check submitted")
+async fn handle_issue_comment(
+    payload: String,
+    job_sender: DataJobSender,
+    pool: actix_web::web::Data<Option<mysql_async::Pool>>,
+) -> Result<&'static str> {
+    let payload: IssueCommentEventPayload = serde_json::from_str(&payload)?;
+
+    if payload.action != "created" || payload.issue.pull_request.is_none() {
+        return Ok("Not a new pull request comment");
+    }
+
+    let Some(request) = parse_commands(&payload.comment.body, "/mdb")
+        .iter()
+        .find_map(|args| parse_command(args))
+    else {
+        return Ok("No commands found");
+    };
+
+    let full_name = payload.repository.full_name();
+
+    if !has_write_access(
+        &full_name,
+        &payload.comment.user.login,
+        payload.installation.id,
+    )
+    .await?
+    {
+        tracing::debug!(
+            "Ignoring command from {}, no write access",
+            payload.comment.user.login
+        );
+        return Ok("Commenter has no write access");
+    }
+
+    let pull_request =
+        get_pull_request(&full_name, payload.issue.number, payload.installation.id).await?;
+
+    submit_check(
+        payload.repository,
+        pull_request,
+        &payload.installation,
+        job_sender,
+        pool.get_ref(),
+        Some(request),
+    )
+    .await
+}
+
+/// Picks up check runs left queued or in progress by a crash, queueing them again if they're
+/// still for the latest commit of an open PR and concluding them as interrupted otherwise
+pub async fn reconcile_stale_checks(pool: Option<mysql_async::Pool>, job_sender: DataJobSender) {
+    let Some(pool) = pool else {
+        tracing::debug!("No database configured, skipping check run reconciliation");
+        return;
+    };
+
+    let candidates: Vec<reconcile::Candidate> = match pool.get_conn().await {
+        Ok(mut conn) => match conn
+            .query(
+                r"SELECT check_id, repo_id, pr_number, installation_id FROM jobs
+                WHERE merge_date IS NULL
+                AND installation_id IS NOT NULL
+                ORDER BY check_id DESC
+                LIMIT 100",
+            )
+            .await
+        {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                tracing::error!("{:?}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            tracing::error!("{:?}", e);
+            return;
+        }
+    };
+
+    let stale = match reconcile::find_stale(&candidates).await {
+        Ok(stale) => stale,
+        Err(e) => {
+            tracing::error!("Failed to look for stale check runs: {:?}", e);
+            return;
+        }
+    };
+
+    for stale in stale {
+        let check_id = stale.check_run.id();
+        let result = if stale.is_current() {
+            tracing::info!("Queueing stale check run {check_id} again");
+            process_pull(
+                stale.repository,
+                stale.pull_request,
+                stale.check_run,
+                &stale.installation,
+                job_sender.clone(),
+                None,
+            )
+            .await
+        } else {
+            tracing::info!("Concluding stale check run {check_id} as interrupted");
+            stale.mark_interrupted().await
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to reconcile check run {check_id}: {:?}", e);
+        }
+    }
+}
+
+/// Queues deleting the data of repositories the app lost access to, and cloning new ones if enabled
+async fn handle_installation(
+    event: &str,
+    payload: String,
+    job_sender: DataJobSender,
+    pool: actix_web::web::Data<Option<mysql_async::Pool>>,
+) -> Result<&'static str> {
+    let Some(change) = parse_installation_event(event, &payload)? else {
+        return Ok("Installation action not relevant");
+    };
+
+    if let Some(ref pool) = pool.get_ref() {
+        let mut conn = pool.get_conn().await?;
+        for repository in change.removed.iter() {
+            if let Err(e) = conn
+                .exec_drop(
+                    r"DELETE FROM jobs WHERE repo_id=:repo_id",
+                    params! {
+                        "repo_id" => repository.id,
+                    },
+                )
+                .await
+            {
+                tracing::error!("{:?}", e);
+            }
+        }
+    }
+
+    for repository in change.removed {
+        tracing::info!("Lost access to {}, removing its data", repository.full_name);
+        job_sender
+            .send_async(JobType::RemoveRepositoryJob(
+                InstallationId(change.installation),
+                repository,
+            ))
+            .await?;
+    }
+
+    if let Some(owner) = change.owner.filter(|_| change.uninstalled) {
+        tracing::info!("Uninstalled from {owner}, removing its clones");
+        job_sender
+            .send_async(JobType::RemoveOwnerJob(owner))
+            .await?;
+    }
+
+    if crate::read_config().preclone_on_install {
+        for repository in change.added {
+            tracing::info!("Installed on {}, queueing a clone", repository.full_name);
+            job_sender
+                .send_async(JobType::CloneJob(
+                    InstallationId(change.installation),
+                    repository,
+                ))
+                .await?;
+        }
+    }
+
+    Ok("Installation change handled")
 }
 
 #[actix_web::post("/payload")]
@@ -230,27 +525,48 @@ pub async fn process_github_payload(
     job_sender: DataJobSender,
     pool: actix_web::web::Data<Option<mysql_async::Pool>>,
 ) -> actix_web::Result<&'static str> {
-    if event.0 != "pull_request" {
+    if event.0 != "pull_request"
+        && event.0 != "issue_comment"
+        && event.0 != "ping"
+        && !is_installation_event(&event.0)
+    {
         return Ok("Not a pull request event");
     }
 
-    let secret = {
-        let conf = &crate::CONFIG.get().unwrap();
-        conf.secret.as_ref()
-    };
-
     diffbot_lib::verify::verify_signature(
-        secret.map(|a| a.as_str()),
+        &crate::read_config().webhook_secrets(),
         event.1.as_deref(),
         &payload,
     )?;
 
+    if event.0 == "ping" {
+        let app_id = crate::CONFIG.get().unwrap().github.app_id;
+        webhook::check_ping(&payload, app_id, &["pull_request", "issue_comment"]).map_err(|e| {
+            tracing::error!("{:?}", e);
+            actix_web::error::ErrorBadRequest(e)
+        })?;
+        return Ok("Pong");
+    }
+
+    let delivery = event.2.as_deref();
+    if !webhook::claim_delivery(delivery) {
+        tracing::info!("Ignoring already processed delivery {:?}", delivery);
+        return Ok("Delivery already processed");
+    }
+
     tracing::debug!("Payload received, processing");
 
-    handle_pull_request(payload, job_sender, pool)
-        .await
-        .map_err(|e| {
-            tracing::error!("Error handling event: {:?}", e);
-            actix_web::error::ErrorBadRequest(e)
-        })
+    let result = match event.0.as_str() {
+        "issue_comment" => handle_issue_comment(payload, job_sender, pool).await,
+        "installation" | "installation_repositories" => {
+            handle_installation(&event.0, payload, job_sender, pool).await
+        }
+        _ => handle_pull_request(payload, job_sender, pool).await,
+    };
+
+    result.map_err(|e| {
+        webhook::release_delivery(delivery);
+        tracing::error!("Error handling event: {:?}", e);
+        actix_web::error::ErrorBadRequest(e)
+    })
 }
@@ -1,7 +1,7 @@
 use eyre::{Context, Result};
 use path_absolutize::Absolutize;
 use secrecy::ExposeSecret;
-use std::io::Read;
+use std::collections::{BTreeSet, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -9,45 +9,66 @@ use super::git_operations::{
     clean_up_references, clone_repo, fetch_and_get_branches, with_checkout,
 };
 
+use crate::lint::lint_map;
 use crate::rendering::{
     get_map_diff_bounding_boxes, load_maps, load_maps_with_whole_map_regions, render_diffs,
-    render_map_regions, MapWithRegions, MapsWithRegions, RenderingContext,
+    render_map_regions, write_to_azure, write_to_file, BoundingBox, MapWithRegions,
+    MapsWithRegions, RenderBudgets, RenderFailures, RenderPasses, RenderWarnings, RenderingContext,
 };
+use crate::repo_config::{MapConfig, REPO_CONFIG_FILE};
 
-use crate::CONFIG;
+use crate::{LimitsConfig, CONFIG};
 
 use diffbot_lib::{
     github::github_types::{
-        Branch, ChangeType, CheckOutputBuilder, CheckOutputs, FileDiff, Output,
+        Branch, ChangeType, CheckOutputBuilder, CheckOutputs, Conclusion, FileDiff, Output,
     },
-    job::types::Job,
+    job::{
+        limits::is_limit_reached,
+        report::{JobReport, REPORT_FILE_NAME},
+        tracker::{self, CancelToken},
+        types::{Job, JobOptions},
+    },
+    repo_config::merge_configs,
     tracing,
 };
 
 use super::Azure;
 
 use rayon::prelude::*;
-use serde::Deserialize;
+use serde::Serialize;
 
 struct RenderedMaps {
-    added_maps: Vec<(String, MapWithRegions)>,
-    removed_maps: Vec<(String, MapWithRegions)>,
+    added_maps: Vec<(String, Result<MapWithRegions>)>,
+    removed_maps: Vec<(String, Result<MapWithRegions>)>,
     modified_maps: MapsWithRegions,
+    render_failures: RenderFailures,
+    /// Maps left out because a limit was reached, reported apart from failures
+    render_skipped: RenderFailures,
+    render_warnings: RenderWarnings,
+    /// Diff images that were written, as `<map>/<z-level>-diff.png`
+    diff_images: HashSet<PathBuf>,
+    lints: Vec<(String, Vec<String>)>,
+    /// What was left out because of resource limits
+    truncated: Vec<String>,
+    config: MapConfig,
+    config_notice: Option<String>,
+    config_error: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct MapConfig {
-    include_pass: String,
-    exclude_pass: String,
-}
-
-fn render(
+fn render<'a>(
     base: &Branch,
     head: &Branch,
-    (added_files, modified_files, removed_files): (&[&FileDiff], &[&FileDiff], &[&FileDiff]),
+    (added_files, modified_files, removed_files): (
+        &[&'a FileDiff],
+        &[&'a FileDiff],
+        &[&'a FileDiff],
+    ),
     (repo, base_branch_name): (&git2::Repository, &str),
     (repo_dir, out_dir, blob_client): (&Path, &Path, Azure),
-    pull_request_number: u64,
+    (pull_request_number, check_run_id): (u64, u64),
+    options: &JobOptions,
+    (cancel, limits): (&CancelToken, &LimitsConfig),
     // feel like this is a bit of a hack but it works for now
 ) -> Result<RenderedMaps> {
     tracing::debug!(
@@ -59,93 +80,134 @@ fn render(
     let pull_branch = format!("mdb-{}-{}", base.sha, head.sha);
     let head_branch = format!("pull/{pull_request_number}/head:{pull_branch}");
 
+    cancel.check()?;
+    let fetch_timer = tracker::enter_stage(check_run_id, "fetch");
     let (base_branch, head_branch) =
         fetch_and_get_branches(&base.sha, &head.sha, repo, &head_branch, base_branch_name)
             .wrap_err("Fetching and constructing diffs")?;
+    drop(fetch_timer);
 
     let path = repo_dir
         .absolutize()
         .wrap_err("Making repo path absolute")?;
 
+    cancel.check()?;
+    let parse_timer = tracker::enter_stage(check_run_id, "parse");
     let base_context = with_checkout(&base_branch, repo, || RenderingContext::new(&path))
         .wrap_err("Parsing base")?;
 
     let head_context = with_checkout(&head_branch, repo, || RenderingContext::new(&path))
         .wrap_err("Parsing head")?;
+    drop(parse_timer);
+
+    let base_config = with_checkout(&base_branch, repo, || MapConfig::read_file(&path))
+        .wrap_err("Reading base config")?;
+    let head_config = with_checkout(&head_branch, repo, || MapConfig::read_file(&path))
+        .wrap_err("Reading head config")?;
+
+    let (config, config_notice, config_error) =
+        match merge_configs(base_config.as_deref(), head_config.as_deref())
+            .and_then(|merged| Ok((MapConfig::from_merged(&merged)?, merged)))
+        {
+            Ok((config, merged)) => (config, merged.notice(REPO_CONFIG_FILE), None),
+            Err(e) => (MapConfig::default(), None, Some(format!("{e:?}"))),
+        };
+
+    let map_filter = config.map_filter()?;
+    let filter_maps = |files: &[&'a FileDiff]| {
+        files
+            .iter()
+            .copied()
+            .filter(|file| map_filter.is_match(&file.filename))
+            .collect::<Vec<_>>()
+    };
+    let (added_files, modified_files, removed_files) = (
+        filter_maps(added_files),
+        filter_maps(modified_files),
+        filter_maps(removed_files),
+    );
 
-    let config = with_checkout(&head_branch, repo, || {
-        let config_path = path.to_path_buf().join("mapdiff.toml");
-        let mut config_str = String::new();
-        std::fs::File::open(config_path)?.read_to_string(&mut config_str)?;
-        let config: MapConfig = toml::from_str(&config_str)?;
-        Ok(config)
-    })
-    .unwrap_or_else(|_| MapConfig {
-        include_pass: "".to_owned(),
-        exclude_pass: "hide-space,hide-invisible,random".to_owned(),
-    });
+    let base_render_passes = RenderPasses::new(base_context.map_config(), &config)?;
 
-    let base_render_passes = dmm_tools::render_passes::configure(
-        base_context.map_config(),
-        &config.include_pass,
-        &config.exclude_pass,
-    );
+    let head_render_passes = RenderPasses::new(head_context.map_config(), &config)?;
 
-    let head_render_passes = dmm_tools::render_passes::configure(
-        head_context.map_config(),
-        &config.include_pass,
-        &config.exclude_pass,
-    );
+    cancel.check()?;
+    let _render_timer = tracker::enter_stage(check_run_id, "render");
+    let budgets = RenderBudgets::new(limits);
+    let total = removed_files.len() + added_files.len() + modified_files.len();
+    let report_progress = |step: &str, done: usize| {
+        tracker::progress(check_run_id, format!("{step}, {done} of {total} maps done"))
+    };
 
     //do removed maps
     let removed_directory = out_dir.to_path_buf().join("r");
     let removed_directory = removed_directory.as_path();
 
-    let removed_errors = Default::default();
-
-    let removed_maps = with_checkout(&base_branch, repo, || {
-        let maps = load_maps_with_whole_map_regions(removed_files, &path)
-            .wrap_err("Loading removed maps")?;
-        render_map_regions(
+    report_progress("rendering removed maps", 0);
+
+    let (removed_maps, removed_renders) = with_checkout(&base_branch, repo, || {
+        let maps = load_maps_with_whole_map_regions(
+            &removed_files,
+            &path,
+            &config,
+            options,
+            limits.max_map_area,
+        );
+        let renders = render_map_regions(
             &base_context,
-            maps.par_iter().map(|(k, v)| (k.as_str(), v)),
+            maps.par_iter()
+                .filter_map(|(k, v)| Some((k.as_str(), v.as_ref().ok()?))),
             &base_render_passes,
             (removed_directory, blob_client.clone()),
             "removed.png",
-            &removed_errors,
             crate::rendering::MapType::Base,
-        )
-        .wrap_err("Rendering removed maps")?;
-        Ok(maps)
-    })?;
+            (cancel, &budgets),
+        );
+        Ok((maps, renders))
+    })
+    .wrap_err("Rendering removed maps")?;
 
     //do added maps
     let added_directory = out_dir.to_path_buf().join("a");
     let added_directory = added_directory.as_path();
 
-    let added_errors = Default::default();
-
-    let added_maps = with_checkout(&head_branch, repo, || {
-        let maps =
-            load_maps_with_whole_map_regions(added_files, &path).wrap_err("Loading added maps")?;
-        render_map_regions(
+    cancel.check()?;
+    report_progress("rendering added maps", removed_files.len());
+
+    let (added_maps, added_renders) = with_checkout(&head_branch, repo, || {
+        let maps = load_maps_with_whole_map_regions(
+            &added_files,
+            &path,
+            &config,
+            options,
+            limits.max_map_area,
+        );
+        let renders = render_map_regions(
             &head_context,
-            maps.par_iter().map(|(k, v)| (k.as_str(), v)),
+            maps.par_iter()
+                .filter_map(|(k, v)| Some((k.as_str(), v.as_ref().ok()?))),
             &head_render_passes,
             (added_directory, blob_client.clone()),
             "added.png",
-            &added_errors,
             crate::rendering::MapType::Head,
-        )
-        .wrap_err("Rendering added maps")?;
-        Ok(maps)
-    })?;
+            (cancel, &budgets),
+        );
+        Ok((maps, renders))
+    })
+    .wrap_err("Rendering added maps")?;
 
     //do modified maps
-    let base_maps = with_checkout(&base_branch, repo, || Ok(load_maps(modified_files, &path)))
-        .wrap_err("Loading base maps")?;
-    let mut head_maps = with_checkout(&head_branch, repo, || Ok(load_maps(modified_files, &path)))
-        .wrap_err("Loading head maps")?;
+    cancel.check()?;
+    let done = removed_files.len() + added_files.len();
+    report_progress("parsing modified maps", done);
+    let base_maps = with_checkout(&base_branch, repo, || {
+        Ok(load_maps(&modified_files, &path, limits.max_map_area))
+    })
+    .wrap_err("Loading base maps")?;
+    let mut head_maps = with_checkout(&head_branch, repo, || {
+        Ok(load_maps(&modified_files, &path, limits.max_map_area))
+    })
+    .wrap_err("Loading head maps")?;
 
     let modified_maps = base_maps
         .into_iter()
@@ -169,68 +231,138 @@ fn render(
         ));
     }
 
-    let modified_maps = get_map_diff_bounding_boxes(modified_maps)?;
+    let modified_maps = get_map_diff_bounding_boxes(modified_maps, &config, options);
 
     let modified_directory = out_dir.to_path_buf().join("m");
     let modified_directory = modified_directory.as_path();
 
-    let modified_before_errors = Default::default();
-    let modified_after_errors = Default::default();
+    cancel.check()?;
+    report_progress("rendering modified maps before the PR", done);
 
-    let before = with_checkout(&base_branch, repo, || {
-        render_map_regions(
+    let mut before = with_checkout(&base_branch, repo, || {
+        Ok(render_map_regions(
             &base_context,
             modified_maps
                 .par_iter()
-                .filter_map(|(map_name, (before, _))| {
-                    Some((map_name.as_str(), before.as_ref().ok()?))
-                }),
+                .filter_map(|(map_name, maps)| Some((map_name.as_str(), &maps.as_ref().ok()?.0))),
             &head_render_passes,
             (modified_directory, blob_client.clone()),
             "before.png",
-            &modified_before_errors,
             crate::rendering::MapType::Base,
-        )
-        .wrap_err("Rendering modified before maps")
-    })?;
+            (cancel, &budgets),
+        ))
+    })
+    .wrap_err("Rendering modified before maps")?;
+
+    cancel.check()?;
+    report_progress("rendering modified maps after the PR", done);
 
-    let after = with_checkout(&head_branch, repo, || {
-        render_map_regions(
+    let mut after = with_checkout(&head_branch, repo, || {
+        Ok(render_map_regions(
             &head_context,
             modified_maps
                 .par_iter()
-                .filter_map(|(map_name, (_, after))| Some((map_name.as_str(), after.as_ref()?))),
+                .filter_map(|(map_name, maps)| Some((map_name.as_str(), &maps.as_ref().ok()?.1))),
             &head_render_passes,
             (modified_directory, blob_client.clone()),
             "after.png",
-            &modified_after_errors,
             crate::rendering::MapType::Head,
-        )
-        .wrap_err("Rendering modified after maps")
-    })?;
+            (cancel, &budgets),
+        ))
+    })
+    .wrap_err("Rendering modified after maps")?;
+
+    cancel.check()?;
+    report_progress("rendering diffs of modified maps", done);
+    // Relative to the modified maps' directory, like the links in the check output
+    let diff_images = render_diffs(
+        std::mem::take(&mut before.images),
+        std::mem::take(&mut after.images),
+        blob_client.clone(),
+        &budgets.output,
+    )
+    .into_iter()
+    .filter_map(|path| Some(path.strip_prefix(modified_directory).ok()?.to_path_buf()))
+    .collect();
 
-    render_diffs(before, after, blob_client.clone());
+    let mut render_failures = RenderFailures::default();
+    let mut render_skipped = RenderFailures::default();
+    let mut render_warnings = RenderWarnings::default();
+    for renders in [removed_renders, added_renders, before, after] {
+        for (map_name, error) in renders.failures {
+            render_failures.entry(map_name).or_insert(error);
+        }
+        for (map_name, error) in renders.skipped {
+            render_skipped.entry(map_name).or_insert(error);
+        }
+        for (map_name, warnings) in renders.warnings {
+            render_warnings
+                .entry(map_name)
+                .or_default()
+                .extend(warnings);
+        }
+    }
+
+    let lints = added_maps
+        .iter()
+        .filter_map(|(map_name, map)| Some((map_name, None, &map.as_ref().ok()?.map)))
+        .chain(modified_maps.iter().filter_map(|(map_name, maps)| {
+            let (before, after) = maps.as_ref().ok()?;
+            Some((map_name, Some(&before.map), &after.map))
+        }))
+        .map(|(map_name, base, head)| (map_name.clone(), lint_map(base, head, &config.lint)))
+        .filter(|(_, warnings)| !warnings.is_empty())
+        .collect();
 
     Ok(RenderedMaps {
         added_maps,
         modified_maps,
         removed_maps,
+        render_failures,
+        render_skipped,
+        render_warnings,
+        diff_images,
+        lints,
+        truncated: budgets.notices().collect(),
+        config,
+        config_notice,
+        config_error,
     })
 }
 
+#[derive(Serialize, Debug)]
+struct MapReport {
+    path: String,
+    status: &'static str,
+    levels: Vec<LevelReport>,
+    error: Option<String>,
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct LevelReport {
+    z: usize,
+    status: &'static str,
+    before_bounds: Option<BoundingBox>,
+    after_bounds: Option<BoundingBox>,
+    before_image: Option<String>,
+    after_image: Option<String>,
+    diff_image: Option<String>,
+}
+
 fn generate_finished_output<P: AsRef<Path>>(
     file_directory: &P,
     maps: RenderedMaps,
+    report: &mut JobReport<MapReport>,
+    installation: u64,
 ) -> Result<CheckOutputs> {
     let conf = CONFIG.get().unwrap();
-    let file_url = if conf.azure_blobs.is_some() {
-        format!(
-            "https://{}.blob.core.windows.net/{}",
-            conf.azure_blobs.as_ref().unwrap().storage_account,
-            conf.azure_blobs.as_ref().unwrap().storage_container
-        )
-    } else {
-        conf.web.file_hosting_url.to_string()
+    let file_url = match (&conf.azure_blobs, conf.storage_container(installation)) {
+        (Some(azure), Some(container)) => format!(
+            "https://{}.blob.core.windows.net/{container}",
+            azure.storage_account,
+        ),
+        _ => conf.web.file_hosting_url.to_string(),
     };
     let non_abs_directory = file_directory
         .as_ref()
@@ -238,51 +370,198 @@ fn generate_finished_output<P: AsRef<Path>>(
         .to_string()
         .replace('\\', "/");
 
-    let mut builder = CheckOutputBuilder::new("Map renderings", &crate::read_config().summary_msg);
-
     let link_base = format!("{file_url}/{non_abs_directory}");
 
+    let parse_errors = maps
+        .added_maps
+        .iter()
+        .chain(maps.removed_maps.iter())
+        .filter_map(|(_, map)| map.as_ref().err())
+        .chain(
+            maps.modified_maps
+                .values()
+                .filter_map(|map| map.as_ref().err()),
+        )
+        .collect::<Vec<_>>();
+    let parse_skipped = parse_errors.iter().filter(|e| is_limit_reached(e)).count();
+    let render_skipped = maps
+        .render_skipped
+        .keys()
+        .filter(|file| !maps.render_failures.contains_key(*file))
+        .count();
+    let skipped = parse_skipped + render_skipped;
+    let failed = parse_errors.len() - parse_skipped + maps.render_failures.len();
+    let truncated = maps
+        .truncated
+        .iter()
+        .map(|notice| format!("**{notice}**\n\n"))
+        .collect::<String>();
+    let cropped = maps
+        .added_maps
+        .iter()
+        .chain(maps.removed_maps.iter())
+        .filter_map(|(file, map)| Some((file, map.as_ref().ok()?.cropped_to?)))
+        .chain(
+            maps.modified_maps
+                .iter()
+                .filter_map(|(file, maps)| Some((file, maps.as_ref().ok()?.0.cropped_to?))),
+        )
+        .map(|(file, (width, height))| {
+            format!(
+                "**Render of `{file}` truncated to {width}x{height} tiles** by `max_width` and `max_height` in `{REPO_CONFIG_FILE}`.\n\n"
+            )
+        })
+        .collect::<String>();
+
+    let mut builder = CheckOutputBuilder::new(
+        "Map renderings",
+        format!(
+            "{}{}{truncated}{cropped}{}[Machine-readable report]({link_base}/{REPORT_FILE_NAME})\n\n{}",
+            match failed {
+                0 => String::new(),
+                failed => format!(
+                    "**{failed} map(s) could not be rendered**, see the `ERROR` entries below.\n\n"
+                ),
+            },
+            match skipped {
+                0 => String::new(),
+                skipped => format!(
+                    "**{skipped} map(s) were skipped** because a limit was reached, see the `SKIPPED` entries below.\n\n"
+                ),
+            },
+            maps.config_notice
+                .as_ref()
+                .map(|notice| format!("{notice}\n\n"))
+                .unwrap_or_default(),
+            crate::read_config().summary_msg
+        ),
+    );
+    if failed > 0 || skipped > 0 || !maps.truncated.is_empty() {
+        builder.set_conclusion(Conclusion::Neutral);
+    }
+
+    if let Some(ref error) = maps.config_error {
+        builder.add_text(&format!(
+            include_str!("../templates/diff_template_config_error.txt"),
+            filename = REPO_CONFIG_FILE,
+            error = error,
+        ));
+        report.add_error(format!("{REPO_CONFIG_FILE}: {error}"));
+    }
+
     // Those are CPU bound but parallelizing would require builder to be thread safe and it's probably not worth the overhead
     maps.added_maps.iter().for_each(|(file, map)| {
+        let map = match map {
+            Ok(map) => map,
+            Err(e) => return add_parse_error(&mut builder, report, file, e),
+        };
+        if let Some(error) = maps.render_failures.get(file) {
+            return add_map_error(&mut builder, report, file, RENDER_ERROR, error.clone());
+        }
+        if let Some(reason) = maps.render_skipped.get(file) {
+            return add_map_error(&mut builder, report, file, SKIPPED, reason.clone());
+        }
         let file_index = file.clone().replace('/', "_").replace(".dmm", "");
+        let mut levels = Vec::new();
         map.iter_levels().for_each(|(level, _)| {
             let link = format!("{link_base}/a/{file_index}/{level}-added.png");
-            let name = format!("{file} (Z-level: {})", level + 1);
+            let name = format!("{file} ({})", maps.config.z_level_name(file, level));
 
             builder.add_text(&format!(
                 include_str!("../templates/diff_template_add.txt"),
                 filename = name,
                 image_link = link
             ));
+
+            levels.push(LevelReport {
+                z: level + 1,
+                status: "added",
+                before_bounds: None,
+                after_bounds: Some(BoundingBox::for_full_map(&map.map)),
+                before_image: None,
+                after_image: Some(link),
+                diff_image: None,
+            });
+        });
+        report.add_file(MapReport {
+            path: file.clone(),
+            status: "added",
+            levels,
+            error: None,
+            warnings: Vec::new(),
         });
     });
 
     maps.removed_maps.iter().for_each(|(file, map)| {
+        let map = match map {
+            Ok(map) => map,
+            Err(e) => return add_parse_error(&mut builder, report, file, e),
+        };
+        if let Some(error) = maps.render_failures.get(file) {
+            return add_map_error(&mut builder, report, file, RENDER_ERROR, error.clone());
+        }
+        if let Some(reason) = maps.render_skipped.get(file) {
+            return add_map_error(&mut builder, report, file, SKIPPED, reason.clone());
+        }
         let file_index = file.clone().replace('/', "_").replace(".dmm", "");
+        let mut levels = Vec::new();
         map.iter_levels().for_each(|(level, _)| {
             let link = format!("{link_base}/r/{file_index}/{level}-removed.png");
-            let name = format!("{file} (Z-level: {})", level + 1);
+            let name = format!("{file} ({})", maps.config.z_level_name(file, level));
 
             builder.add_text(&format!(
                 include_str!("../templates/diff_template_remove.txt"),
                 filename = name,
                 image_link = link
             ));
+
+            levels.push(LevelReport {
+                z: level + 1,
+                status: "removed",
+                before_bounds: Some(BoundingBox::for_full_map(&map.map)),
+                after_bounds: None,
+                before_image: Some(link),
+                after_image: None,
+                diff_image: None,
+            });
+        });
+        report.add_file(MapReport {
+            path: file.clone(),
+            status: "removed",
+            levels,
+            error: None,
+            warnings: Vec::new(),
         });
     });
 
     const Z_DELETED_TEXT: &str = "Z-LEVEL DELETED";
     const Z_ADDED_TEXT: &str = "Z-LEVEL ADDED";
+    const DIFF_MISSING_TEXT: &str = "DIFF NOT RENDERED";
     const ROW_DESC: &str = "If the image doesn't load, use the raw link above";
 
     maps.modified_maps
         .iter()
-        .for_each(|(file, (before, _))| match before {
-            Ok(map) => {
+        .for_each(|(file, maps_result)| match maps_result {
+            Ok(_) if maps.render_failures.contains_key(file) => add_map_error(
+                &mut builder,
+                report,
+                file,
+                RENDER_ERROR,
+                maps.render_failures[file].clone(),
+            ),
+            Ok(_) if maps.render_skipped.contains_key(file) => add_map_error(
+                &mut builder,
+                report,
+                file,
+                SKIPPED,
+                maps.render_skipped[file].clone(),
+            ),
+            Ok((map, _)) => {
                 let file_index = file.clone().replace('/', "_").replace(".dmm", "");
+                let mut levels = Vec::new();
                 map.iter_levels().for_each(|(level, region)| {
                     let link = format!("{link_base}/m/{file_index}/{level}");
-                    let name = format!("{file} (Z-level: {})", level + 1);
+                    let name = format!("{file} ({})", maps.config.z_level_name(file, level));
                     let (dim_x, dim_y, _) = map.map.dim_xyz();
                     let fmt_dim = format!("({dim_x}, {dim_y}, {})", level + 1);
 
@@ -306,6 +585,15 @@ fn generate_finished_output<P: AsRef<Path>>(
                                 new_row = format_args!("![{ROW_DESC}]({link_after})"),
                                 diff_row = Z_ADDED_TEXT
                             ));
+                            levels.push(LevelReport {
+                                z: level + 1,
+                                status: "z-level added",
+                                before_bounds: None,
+                                after_bounds: Some(BoundingBox::for_full_map(&map.map)),
+                                before_image: None,
+                                after_image: Some(link_after),
+                                diff_image: None,
+                            });
                         }
                         crate::rendering::BoundType::OnlyBase => {
                             builder.add_text(&format!(
@@ -319,20 +607,50 @@ fn generate_finished_output<P: AsRef<Path>>(
                                 new_row = Z_DELETED_TEXT,
                                 diff_row = Z_DELETED_TEXT
                             ));
+                            levels.push(LevelReport {
+                                z: level + 1,
+                                status: "z-level deleted",
+                                before_bounds: None,
+                                after_bounds: None,
+                                before_image: None,
+                                after_image: None,
+                                diff_image: None,
+                            });
                         }
                         crate::rendering::BoundType::Both((base_bound, head_bound)) => {
                             if base_bound == head_bound {
+                                // Left out when the output budget ran out or nothing differed
+                                let diff_written = maps
+                                    .diff_images
+                                    .contains(Path::new(&format!("{file_index}/{level}-diff.png")));
+                                let (image_diff_link, diff_row) = if diff_written {
+                                    (
+                                        format!("[Diff]({link_diff})"),
+                                        format!("![{ROW_DESC}]({link_diff})"),
+                                    )
+                                } else {
+                                    ("Unavailable".to_owned(), DIFF_MISSING_TEXT.to_owned())
+                                };
                                 builder.add_text(&format!(
                                     include_str!("../templates/diff_template_mod.txt"),
                                     bounds = base_bound.to_string(),
                                     filename = name,
                                     image_before_link = format_args!("[Old]({link_before})"),
                                     image_after_link = format_args!("[New]({link_after})"),
-                                    image_diff_link = format_args!("[Diff]({link_diff})"),
+                                    image_diff_link = image_diff_link,
                                     old_row = format_args!("![{ROW_DESC}]({link_before})"),
                                     new_row = format_args!("![{ROW_DESC}]({link_after})"),
-                                    diff_row = format_args!("![{ROW_DESC}]({link_diff})")
+                                    diff_row = diff_row
                                 ));
+                                levels.push(LevelReport {
+                                    z: level + 1,
+                                    status: "modified",
+                                    before_bounds: Some(*base_bound),
+                                    after_bounds: Some(*head_bound),
+                                    before_image: Some(link_before),
+                                    after_image: Some(link_after),
+                                    diff_image: diff_written.then_some(link_diff),
+                                });
                             } else {
                                 builder.add_text(&format!(
                                     include_str!("../templates/diff_template_sizechanged.txt"),
@@ -342,25 +660,139 @@ fn generate_finished_output<P: AsRef<Path>>(
                                     old_row = format_args!("![{ROW_DESC}]({link_before})"),
                                     new_row = format_args!("![{ROW_DESC}]({link_after})"),
                                 ));
+                                levels.push(LevelReport {
+                                    z: level + 1,
+                                    status: "size changed",
+                                    before_bounds: Some(*base_bound),
+                                    after_bounds: Some(*head_bound),
+                                    before_image: Some(link_before),
+                                    after_image: Some(link_after),
+                                    diff_image: None,
+                                });
                             }
                         }
                     }
                 });
+                report.add_file(MapReport {
+                    path: file.clone(),
+                    status: "modified",
+                    levels,
+                    error: None,
+                    warnings: Vec::new(),
+                });
             }
-            Err(e) => {
-                let error = format!("{e:?}");
-                builder.add_text(&format!(
-                    include_str!("../templates/diff_template_error.txt"),
-                    filename = file,
-                    error = error,
-                ));
-            }
+            Err(e) => add_parse_error(&mut builder, report, file, e),
         });
 
+    maps.lints.iter().for_each(|(file, warnings)| {
+        builder.add_text(&format!(
+            include_str!("../templates/diff_template_lint.txt"),
+            filename = file,
+            warnings = warnings
+                .iter()
+                .map(|warning| format!("- {warning}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ));
+    });
+
+    if !maps.render_warnings.is_empty() {
+        builder.add_text(&format!(
+            include_str!("../templates/diff_template_render_warnings.txt"),
+            count = maps.render_warnings.len(),
+            warnings = maps
+                .render_warnings
+                .iter()
+                .map(|(file, warnings)| format_render_warnings(file, warnings))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        ));
+    }
+    for file in report.files.iter_mut() {
+        if let Some(warnings) = maps.render_warnings.get(&file.path) {
+            file.warnings = warnings.iter().cloned().collect();
+        }
+    }
+
     Ok(builder.build())
 }
 
-pub fn do_job(job: Job, blob_client: Azure) -> Result<CheckOutputs> {
+/// Only this many warnings are listed per map in the check output, the report has all of them
+const MAX_RENDER_WARNINGS: usize = 50;
+
+fn format_render_warnings(file: &str, warnings: &BTreeSet<String>) -> String {
+    let mut lines = vec![format!("**{file}**")];
+    lines.extend(
+        warnings
+            .iter()
+            .take(MAX_RENDER_WARNINGS)
+            .map(|warning| format!("- `{warning}`")),
+    );
+    if warnings.len() > MAX_RENDER_WARNINGS {
+        lines.push(format!(
+            "- ...and {} more",
+            warnings.len() - MAX_RENDER_WARNINGS
+        ));
+    }
+    lines.join("\n")
+}
+
+const PARSE_ERROR: &str = "parse error";
+const RENDER_ERROR: &str = "render error";
+const SKIPPED: &str = "skipped";
+
+/// Maps too large to load are reported as skipped rather than as broken
+fn add_parse_error(
+    builder: &mut CheckOutputBuilder,
+    report: &mut JobReport<MapReport>,
+    file: &str,
+    error: &eyre::Report,
+) {
+    if is_limit_reached(error) {
+        add_map_error(builder, report, file, SKIPPED, error.to_string());
+    } else {
+        add_map_error(builder, report, file, PARSE_ERROR, format!("{error:?}"));
+    }
+}
+
+/// Adds the row of a map that couldn't be parsed or rendered
+fn add_map_error(
+    builder: &mut CheckOutputBuilder,
+    report: &mut JobReport<MapReport>,
+    file: &str,
+    status: &'static str,
+    error: String,
+) {
+    builder.add_text(&format!(
+        include_str!("../templates/diff_template_error.txt"),
+        status = status.to_uppercase(),
+        filename = file,
+        error = error,
+    ));
+    report.add_file(MapReport {
+        path: file.to_owned(),
+        status,
+        levels: Vec::new(),
+        error: Some(error),
+        warnings: Vec::new(),
+    });
+}
+
+/// Writes the json report to the same place the images went
+fn write_report(
+    output_directory: &Path,
+    report: &mut JobReport<MapReport>,
+    blob_client: Azure,
+) -> Result<()> {
+    let path = output_directory.join(REPORT_FILE_NAME);
+    let report = report.finish()?;
+    match blob_client {
+        Some(client) => write_to_azure(&path, client, &report).wrap_err("Sending report to azure"),
+        None => write_to_file(&path, &report).wrap_err("Writing report to file"),
+    }
+}
+
+pub fn do_job(job: Job, blob_client: Azure, cancel: CancelToken) -> Result<CheckOutputs> {
     tracing::debug!(
         "Starting Job on repo: {}, pr number: {}, base commit: {}, head commit: {}",
         job.repo.full_name(),
@@ -369,6 +801,8 @@ pub fn do_job(job: Job, blob_client: Azure) -> Result<CheckOutputs> {
         job.head.sha
     );
 
+    let mut report = JobReport::new(&job);
+
     let base = &job.base;
     let head = &job.head;
 
@@ -395,6 +829,7 @@ pub fn do_job(job: Job, blob_client: Azure) -> Result<CheckOutputs> {
                 };
                 _ = job.check_run.set_output(output).await; // we don't really care if updating the job fails, just continue
             });
+        let _clone_timer = tracker::enter_stage(job.check_run.id(), "clone");
         clone_repo(&url, &repo_dir).wrap_err("Cloning repo")?;
     }
 
@@ -464,12 +899,21 @@ pub fn do_job(job: Job, blob_client: Azure) -> Result<CheckOutputs> {
         head,
         (&added_files, &modified_files, &removed_files),
         (&repository, &job.base.r#ref),
-        (&repo_dir, output_directory, blob_client),
-        job.pull_request,
+        (&repo_dir, output_directory, blob_client.clone()),
+        (job.pull_request, job.check_run.id()),
+        &job.options,
+        (&cancel, &crate::read_config().limits(job.installation.0)),
     )
     .wrap_err("")
     {
-        Ok(maps) => generate_finished_output(&non_abs_directory, maps),
+        Ok(mut maps) => {
+            maps.truncated.splice(0..0, job.truncated.iter().cloned());
+            generate_finished_output(&non_abs_directory, maps, &mut report, job.installation.0)
+                .and_then(|outputs| {
+                    write_report(output_directory, &mut report, blob_client)?;
+                    Ok(outputs)
+                })
+        }
         Err(err) => Err(err),
     };
 
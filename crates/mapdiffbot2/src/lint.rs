@@ -0,0 +1,70 @@
+use crate::repo_config::LintConfig;
+use dmm_tools::dmm;
+use indexmap::IndexSet;
+use std::collections::BTreeSet;
+
+/// Checks the tiles a PR changed against the repo's `[lint]` settings, returns one line per
+/// problem found. Every tile of the map is checked if there is no base version.
+pub fn lint_map(base: Option<&dmm::Map>, head: &dmm::Map, config: &LintConfig) -> Vec<String> {
+    if config.forbidden_paths.is_empty() && !config.duplicate_objects {
+        return Vec::new();
+    }
+    let mut warnings: IndexSet<String, ahash::RandomState> = Default::default();
+
+    for key in changed_keys(base, head) {
+        let prefabs = &head.dictionary[&key];
+        for (index, prefab) in prefabs.iter().enumerate() {
+            if let Some(forbidden) = config
+                .forbidden_paths
+                .iter()
+                .find(|forbidden| is_subtype(&prefab.path, forbidden))
+            {
+                warnings.insert(format!(
+                    "`{}` is placed on the map, but `{forbidden}` is forbidden",
+                    prefab.path
+                ));
+            }
+
+            if config.duplicate_objects
+                && prefab.path.starts_with("/obj/")
+                && prefabs[..index].contains(prefab)
+            {
+                warnings.insert(format!(
+                    "Identical `{}` objects are stacked on the same tile",
+                    prefab.path
+                ));
+            }
+        }
+    }
+
+    warnings.into_iter().collect()
+}
+
+/// Keys of the head map placed on tiles whose contents differ from the base map. Maps that
+/// changed size are rendered whole, so all of their tiles count as changed.
+fn changed_keys(base: Option<&dmm::Map>, head: &dmm::Map) -> BTreeSet<dmm::Key> {
+    let base = base.filter(|base| {
+        let (base_x, base_y, _) = base.dim_xyz();
+        let (head_x, head_y, _) = head.dim_xyz();
+        (base_x, base_y) == (head_x, head_y)
+    });
+    head.grid
+        .indexed_iter()
+        .filter(|&(index, key)| {
+            base.is_none_or(|base| {
+                base.grid
+                    .get(index)
+                    .is_none_or(|base_key| base.dictionary[base_key] != head.dictionary[key])
+            })
+        })
+        .map(|(_, key)| *key)
+        .collect()
+}
+
+fn is_subtype(path: &str, parent: &str) -> bool {
+    let parent = parent.trim_end_matches('/');
+    path == parent
+        || path
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
@@ -2,15 +2,19 @@ mod gc_job;
 mod git_operations;
 mod github_processor;
 mod job_processor;
+mod lint;
 mod rendering;
+mod repo_config;
 mod runner;
 
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
 
+use diffbot_lib::job::types::JobType;
+use eyre::WrapErr;
 use mysql_async::prelude::Queryable;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::sync::OnceLock;
 
 #[cfg(not(target_env = "msvc"))]
@@ -57,6 +61,49 @@ pub struct GrafanaLoki {
     url: String,
 }
 
+/// PR labels that control the bot, unset labels are ignored
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct LabelConfig {
+    pub skip: Option<String>,
+    pub full_render: Option<String>,
+    pub all_z_levels: Option<String>,
+}
+
+impl LabelConfig {
+    pub fn is_relevant(&self, label: &str) -> bool {
+        [&self.skip, &self.full_render, &self.all_z_levels]
+            .into_iter()
+            .flatten()
+            .any(|name| name.eq_ignore_ascii_case(label))
+    }
+}
+
+/// Guardrails against enormous PRs, the check run concludes as neutral if one of them is hit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Changed maps rendered per job
+    pub max_files: usize,
+    /// Tiles per z-level, larger maps are not rendered
+    pub max_map_area: usize,
+    /// Pixels rendered per job, counted as 32x32 per tile
+    pub max_rendered_pixels: u64,
+    /// Bytes of images stored per job
+    pub max_output_bytes: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_files: 50,
+            max_map_area: 500 * 500,
+            max_rendered_pixels: 1_000_000_000,
+            max_output_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub github: GithubConfig,
@@ -72,15 +119,87 @@ pub struct Config {
     #[serde(default = "default_msg")]
     pub summary_msg: String,
     pub secret: Option<String>,
+    /// Secrets that are still accepted while the webhook secret is being rotated
+    #[serde(default)]
+    pub previous_secrets: Vec<String>,
+    /// Allows running without a webhook secret, for local testing
+    #[serde(default)]
+    pub dev_mode: bool,
+    pub admin_token: Option<String>,
+    #[serde(default = "default_grace_period")]
+    pub shutdown_grace_period: u64,
+    #[serde(default = "default_job_timeout")]
+    pub job_timeout: u64,
+    /// Job timeouts for specific repositories, keyed by `owner/repo`
+    #[serde(default)]
+    pub repo_job_timeouts: std::collections::HashMap<String, u64>,
     pub db_url: Option<String>,
     pub azure_blobs: Option<AzureBlobs>,
     pub grafana_loki: Option<GrafanaLoki>,
+    #[serde(default)]
+    pub labels: LabelConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Clone repositories as soon as the app is installed on them
+    #[serde(default)]
+    pub preclone_on_install: bool,
+    #[serde(default)]
+    pub policy: diffbot_lib::policy::PolicyConfig,
 }
 
 fn default_schedule() -> String {
     "0 0 4 * * *".to_string()
 }
 
+impl Config {
+    /// The current webhook secret followed by the ones being rotated out
+    pub fn webhook_secrets(&self) -> Vec<&str> {
+        self.secret
+            .iter()
+            .chain(self.previous_secrets.iter())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Container the installation's images are stored in, if they are stored on azure
+    pub fn storage_container(&self, installation: u64) -> Option<&str> {
+        let azure = self.azure_blobs.as_ref()?;
+        Some(
+            self.policy
+                .storage_container(installation)
+                .unwrap_or(&azure.storage_container),
+        )
+    }
+
+    /// The limits with the installation's policy overrides applied
+    pub fn limits(&self, installation: u64) -> LimitsConfig {
+        self.policy
+            .limits(installation, &self.limits)
+            .unwrap_or_else(|e| {
+                diffbot_lib::tracing::error!("{e:?}");
+                self.limits.clone()
+            })
+    }
+
+    /// How long a job for the repository may run before it is stopped
+    pub fn job_timeout(&self, full_repo: &str) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            self.repo_job_timeouts
+                .get(full_repo)
+                .copied()
+                .unwrap_or(self.job_timeout),
+        )
+    }
+}
+
+fn default_grace_period() -> u64 {
+    300
+}
+
+fn default_job_timeout() -> u64 {
+    7200
+}
+
 fn default_log_level() -> String {
     "info".to_string()
 }
@@ -107,7 +226,13 @@ fn init_config(path: &std::path::Path) -> eyre::Result<&'static Config> {
     let mut config_str = String::new();
     File::open(path)?.read_to_string(&mut config_str)?;
 
-    let config = toml::from_str(&config_str)?;
+    let config: Config = toml::from_str(&config_str)?;
+    config.policy.validate(&config.limits)?;
+    if config.secret.as_deref().unwrap_or_default().is_empty() && !config.dev_mode {
+        eyre::bail!(
+            "No webhook secret is set, set `secret` or enable `dev_mode` to accept unsigned payloads"
+        );
+    }
 
     CONFIG.set(config).expect("Failed to set config");
     Ok(CONFIG.get().unwrap())
@@ -119,13 +244,29 @@ fn read_config() -> &'static Config {
 
 type Azure = Option<std::sync::Arc<object_store::azure::MicrosoftAzure>>;
 
+fn azure_client(
+    azure: &AzureBlobs,
+    container: &str,
+) -> object_store::Result<std::sync::Arc<object_store::azure::MicrosoftAzure>> {
+    Ok(std::sync::Arc::new(
+        object_store::azure::MicrosoftAzureBuilder::new()
+            .with_account(azure.storage_account.clone())
+            .with_access_key(azure.storage_access_key.clone())
+            .with_container_name(container)
+            .with_client_options(
+                object_store::ClientOptions::new().with_content_type_for_suffix("png", "image/png"),
+            )
+            .build()?,
+    ))
+}
+
 #[actix_web::main]
 async fn main() -> eyre::Result<()> {
     simple_eyre::install().expect("Eyre handler installation failed!");
 
     let config_path = std::path::Path::new(".").join("config.toml");
     let config =
-        init_config(&config_path).unwrap_or_else(|_| panic!("Failed to read {config_path:?}"));
+        init_config(&config_path).wrap_err_with(|| format!("Failed to load {config_path:?}"))?;
 
     let (layer, tasks) = if let Some(ref loki_config) = config.grafana_loki {
         let (layer, tasks) = tracing_loki::builder()
@@ -154,6 +295,7 @@ async fn main() -> eyre::Result<()> {
     );
 
     let (job_sender, job_receiver) = flume::unbounded();
+    diffbot_lib::metrics::observe_queue(job_receiver.clone());
 
     let pool = config
         .db_url
@@ -169,33 +311,44 @@ async fn main() -> eyre::Result<()> {
                 `pr_number` INT(11) NOT NULL,
                 `merge_date` DATETIME NULL DEFAULT NULL,
                 `processed` BIT(1) NOT NULL DEFAULT b'0',
+                `retention_days` INT(11) NULL DEFAULT NULL,
+                `installation_id` BIGINT(20) NULL DEFAULT NULL,
                 PRIMARY KEY (`check_id`) USING BTREE,
                 INDEX `merge_date` (`processed`) USING BTREE,
                 INDEX `processed` (`processed`) USING BTREE
             ) COLLATE='utf8mb4_general_ci' ENGINE=InnoDB;",
         )
         .await?;
+        diffbot_lib::db::add_missing_columns(&mut conn).await?;
     }
 
     let blob_client = config.azure_blobs.as_ref().map(|azure| {
-        std::sync::Arc::new(
-            object_store::azure::MicrosoftAzureBuilder::new()
-                .with_account(azure.storage_account.clone())
-                .with_access_key(azure.storage_access_key.clone())
-                .with_container_name(azure.storage_container.clone())
-                .with_client_options(
-                    object_store::ClientOptions::new()
-                        .with_content_type_for_suffix("png", "image/png"),
-                )
-                .build()
-                .expect("Trying to connect to azure"),
-        )
+        azure_client(azure, &azure.storage_container).expect("Trying to connect to azure")
     });
 
+    let (preclone_sender, preclone_receiver) = flume::unbounded();
+    actix_web::rt::spawn(runner::handle_preclones(preclone_receiver));
+
     actix_web::rt::spawn(runner::handle_jobs(
         "MapDiffBot2",
         job_receiver,
         blob_client,
+        pool.clone(),
+        preclone_sender,
+    ));
+
+    match diffbot_lib::job::shutdown::restore().await {
+        Ok(jobs) => {
+            for job in jobs {
+                job_sender.send(JobType::GithubJob(Box::new(job)))?;
+            }
+        }
+        Err(e) => diffbot_lib::tracing::error!("Failed to restore unfinished jobs: {e:?}"),
+    }
+
+    actix_web::rt::spawn(github_processor::reconcile_stale_checks(
+        pool.clone(),
+        actix_web::web::Data::new(job_sender.clone()),
     ));
 
     let job_clone = job_sender.clone();
@@ -204,6 +357,16 @@ async fn main() -> eyre::Result<()> {
 
     actix_web::rt::spawn(async move { gc_job::gc_scheduler(cron_str, job_clone).await });
 
+    let admin = {
+        let (requeue_sender, cleanup_sender) = (job_sender.clone(), job_sender.clone());
+        actix_web::web::Data::new(
+            diffbot_lib::admin::Admin::new("MapDiffBot2", config.admin_token.clone(), move |job| {
+                Ok(requeue_sender.send(JobType::GithubJob(Box::new(job)))?)
+            })
+            .with_cleanup(move || Ok(cleanup_sender.send(JobType::CleanupJob)?)),
+        )
+    };
+
     actix_web::HttpServer::new(move || {
         let pool = pool.clone();
         use actix_web::web::{FormConfig, PayloadConfig};
@@ -223,12 +386,21 @@ async fn main() -> eyre::Result<()> {
             .app_data(string_config)
             .app_data(actix_web::web::Data::new(job_sender.clone()))
             .app_data(actix_web::web::Data::new(pool))
+            .app_data(admin.clone())
             .service(index)
+            .service(diffbot_lib::admin::scope())
+            .service(diffbot_lib::metrics::metrics_endpoint)
             .service(github_processor::process_github_payload)
             .service(actix_files::Files::new("/images", "./images"))
     })
     .bind((config.web.address.as_ref(), config.web.port))?
     .run()
     .await?;
-    Ok(())
+
+    diffbot_lib::job::shutdown::shutdown(std::time::Duration::from_secs(
+        config.shutdown_grace_period,
+    ))
+    .await;
+    // A render that outlived the grace period would keep the runtime from shutting down
+    std::process::exit(0)
 }
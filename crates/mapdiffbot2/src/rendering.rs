@@ -1,6 +1,6 @@
 use std::{
     cmp::min,
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
     io::Write,
     path::{Path, PathBuf},
     sync::RwLock,
@@ -8,9 +8,14 @@ use std::{
 
 extern crate dreammaker;
 
-use diffbot_lib::tracing;
+use diffbot_lib::{metrics, tracing};
 
 use diffbot_lib::github::github_types::FileDiff;
+use diffbot_lib::job::{
+    limits::{is_limit_reached, Budget, LimitReached},
+    tracker::CancelToken,
+    types::JobOptions,
+};
 use dmm_tools::{dmm, minimap, render_passes::RenderPass, IconCache};
 use dreammaker::objtree::ObjectTree;
 use eyre::{Context, Result};
@@ -22,8 +27,9 @@ use ahash::RandomState;
 use indexmap::IndexMap;
 
 use super::Azure;
+use crate::repo_config::MapConfig;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
 pub struct BoundingBox {
     left: usize,
     bottom: usize,
@@ -50,6 +56,35 @@ impl BoundingBox {
             top: dims.1 - 1,
         }
     }
+
+    /// Size in tiles
+    pub fn area(&self) -> usize {
+        self.width() * self.height()
+    }
+
+    pub fn width(&self) -> usize {
+        self.right - self.left + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.top - self.bottom + 1
+    }
+
+    /// Crops the box to the given size in tiles, keeping the bottom left corner in place
+    pub fn limited(self, max_width: Option<usize>, max_height: Option<usize>) -> Self {
+        let mut limited = self;
+        if let Some(max_width) = max_width {
+            limited.right = limited
+                .right
+                .min(limited.left + max_width.saturating_sub(1));
+        }
+        if let Some(max_height) = max_height {
+            limited.top = limited
+                .top
+                .min(limited.bottom + max_height.saturating_sub(1));
+        }
+        limited
+    }
 }
 
 impl std::fmt::Display for BoundingBox {
@@ -64,11 +99,51 @@ impl std::fmt::Display for BoundingBox {
 
 pub type RenderingErrors = RwLock<HashSet<String, RandomState>>;
 
+/// Render passes to use for each map, taking the repo config's overrides into account
+pub struct RenderPasses {
+    default: Vec<Box<dyn RenderPass>>,
+    overrides: Vec<(globset::GlobMatcher, Vec<Box<dyn RenderPass>>)>,
+}
+
+impl RenderPasses {
+    pub fn new(map_renderer: &dreammaker::config::MapRenderer, config: &MapConfig) -> Result<Self> {
+        let default = dmm_tools::render_passes::configure(
+            map_renderer,
+            &config.include_pass,
+            &config.exclude_pass,
+        );
+        let overrides = config
+            .overrides
+            .iter()
+            .map(|item| -> Result<_> {
+                Ok((
+                    item.matcher()?,
+                    dmm_tools::render_passes::configure(
+                        map_renderer,
+                        item.include_pass.as_ref().unwrap_or(&config.include_pass),
+                        item.exclude_pass.as_ref().unwrap_or(&config.exclude_pass),
+                    ),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { default, overrides })
+    }
+
+    pub fn for_map(&self, map_name: &str) -> &[Box<dyn RenderPass>] {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.is_match(map_name))
+            .map_or(&self.default, |(_, passes)| passes)
+    }
+}
+
 // Returns None if there are no differences
 pub fn get_diff_bounding_box(
     base_map: &dmm::Map,
     head_map: &dmm::Map,
     z_level: usize,
+    padding: usize,
 ) -> Option<(BoundingBox, BoundingBox)> {
     let left_dims = base_map.dim_xyz();
     let right_dims = head_map.dim_xyz();
@@ -128,10 +203,16 @@ pub fn get_diff_bounding_box(
 
     //this is a god awful way to expand bounds without it going out of bounds
 
-    rightmost = rightmost.saturating_add(2).clamp(1, (max_x - 1).max(1));
-    topmost = topmost.saturating_add(2).clamp(1, (max_y - 1).max(1));
-    leftmost = leftmost.saturating_sub(2).clamp(1, (max_x - 1).max(1));
-    bottommost = bottommost.saturating_sub(2).clamp(1, (max_y - 1).max(1));
+    rightmost = rightmost
+        .saturating_add(padding)
+        .clamp(1, (max_x - 1).max(1));
+    topmost = topmost.saturating_add(padding).clamp(1, (max_y - 1).max(1));
+    leftmost = leftmost
+        .saturating_sub(padding)
+        .clamp(1, (max_x - 1).max(1));
+    bottommost = bottommost
+        .saturating_sub(padding)
+        .clamp(1, (max_y - 1).max(1));
 
     tracing::debug!(
         "After expansion max: (right, top):({rightmost}, {topmost}), min: (left, bottom):({leftmost}, {bottommost})",
@@ -143,48 +224,84 @@ pub fn get_diff_bounding_box(
     ))
 }
 
+/// Parses a map, keeping the line and column the parser stopped at in the error
+fn parse_map(root: &Path, filename: &str, max_map_area: usize) -> Result<dmm::Map> {
+    let map = dmm::Map::from_file(&root.join(Path::new(filename))).map_err(|e| {
+        let location = e.location();
+        eyre::eyre!(
+            "Failed to parse {filename} at line {}, column {}: {}",
+            location.line,
+            location.column,
+            e.description()
+        )
+    })?;
+
+    let (width, height, _) = map.dim_xyz();
+    if width * height > max_map_area {
+        return Err(LimitReached(format!(
+            "Skipped, {filename} is {width}x{height} tiles, more than the limit of {max_map_area} tiles per z-level"
+        ))
+        .into());
+    }
+    Ok(map)
+}
+
 pub fn load_maps(
     files: &[&FileDiff],
     path: &std::path::Path,
+    max_map_area: usize,
 ) -> IndexMap<String, Result<dmm::Map>, RandomState> {
     files
         .iter()
         .map(|file| {
-            let actual_path = path.join(Path::new(&file.filename));
             (
                 file.filename.clone(),
-                dmm::Map::from_file(&actual_path)
-                    .map_err(|e| eyre::anyhow!(e))
-                    .wrap_err_with(|| format!("Map name: {}", &file.filename)),
+                parse_map(path, &file.filename, max_map_area),
             )
         })
         .collect()
 }
 
+/// Maps that fail to parse are kept with their error so they can be reported on their own
 pub fn load_maps_with_whole_map_regions(
     files: &[&FileDiff],
     path: &std::path::Path,
-) -> Result<Vec<(String, MapWithRegions)>> {
+    config: &MapConfig,
+    options: &JobOptions,
+    max_map_area: usize,
+) -> Vec<(String, Result<MapWithRegions>)> {
     files
         .iter()
         .map(|file| {
-            let actual_path = path.join(Path::new(&file.filename));
-            let map = dmm::Map::from_file(&actual_path)?;
-            let bbox = BoundingBox::for_full_map(&map);
-            let zs = map.dim_z();
-            Ok((
-                file.filename.clone(),
+            let map = parse_map(path, &file.filename, max_map_area).map(|map| {
+                let bbox = BoundingBox::for_full_map(&map);
+                let zs = map.dim_z();
+                let mut bounding_boxes: Vec<_> = (0..zs)
+                    .map(|z| {
+                        if wants_z_level(options, z) {
+                            BoundType::Both((bbox, bbox))
+                        } else {
+                            BoundType::None
+                        }
+                    })
+                    .collect();
+                let cropped_to = limit_regions(&mut bounding_boxes, config);
                 MapWithRegions {
                     map,
-                    bounding_boxes: std::iter::repeat(BoundType::Both((bbox, bbox)))
-                        .take(zs)
-                        .collect(),
-                },
-            ))
+                    bounding_boxes,
+                    cropped_to,
+                }
+            });
+            (file.filename.clone(), map)
         })
         .collect()
 }
 
+/// Whether the given zero indexed z-level was asked to be rendered
+fn wants_z_level(options: &JobOptions, z: usize) -> bool {
+    options.z_level.is_none_or(|wanted| wanted == z + 1)
+}
+
 #[derive(Clone)]
 pub enum BoundType {
     OnlyHead,
@@ -197,6 +314,29 @@ pub struct MapWithRegions {
     pub map: dmm::Map,
     /// For each z-level, if there's a Some, render the given region
     pub bounding_boxes: Vec<BoundType>,
+    /// Largest size in tiles a region was cropped to by `max_width` and `max_height`, if any was
+    pub cropped_to: Option<(usize, usize)>,
+}
+
+/// Crops every region to the repo config's maximum size, returns the largest cropped size
+fn limit_regions(regions: &mut [BoundType], config: &MapConfig) -> Option<(usize, usize)> {
+    let mut cropped_to: Option<(usize, usize)> = None;
+    for region in regions.iter_mut() {
+        let BoundType::Both(bounds) = region else {
+            continue;
+        };
+        let limited = (
+            bounds.0.limited(config.max_width, config.max_height),
+            bounds.1.limited(config.max_width, config.max_height),
+        );
+        if limited != *bounds {
+            let (width, height) = cropped_to.get_or_insert((0, 0));
+            *width = (*width).max(limited.0.width()).max(limited.1.width());
+            *height = (*height).max(limited.0.height()).max(limited.1.height());
+        }
+        *bounds = limited;
+    }
+    cropped_to
 }
 // pub fn iter_levels<'a>(&'a self) -> impl Iterator<Item=(i32, ZLevel<'a>)> + 'a {
 impl MapWithRegions {
@@ -214,13 +354,14 @@ impl MapWithRegions {
     }
 }
 
-pub type MapsWithRegions = IndexMap<String, (RegionsBefore, RegionsAfter), RandomState>;
-pub type RegionsAfter = Option<MapWithRegions>;
-pub type RegionsBefore = Result<MapWithRegions>;
+/// Before and after version of every modified map, or why either side couldn't be parsed
+pub type MapsWithRegions = IndexMap<String, Result<(MapWithRegions, MapWithRegions)>, RandomState>;
 
 pub fn get_map_diff_bounding_boxes(
     modified_maps: IndexMap<String, (Result<dmm::Map>, Result<dmm::Map>), RandomState>,
-) -> Result<MapsWithRegions> {
+    config: &MapConfig,
+    options: &JobOptions,
+) -> MapsWithRegions {
     use itertools::{EitherOrBoth, Itertools};
 
     let mut returned_maps =
@@ -229,37 +370,58 @@ pub fn get_map_diff_bounding_boxes(
     for (map_name, (base, head)) in modified_maps.into_iter() {
         match (base, head) {
             (Ok(base), Ok(head)) => {
-                let diffs = (0..base.dim_z())
+                let mut diffs = (0..base.dim_z())
                     .zip_longest(0..head.dim_z())
                     .map(|either| match either {
-                        EitherOrBoth::Both(z, _) => match get_diff_bounding_box(&base, &head, z) {
-                            Some(boxed) => BoundType::Both(boxed),
-                            None => BoundType::None,
-                        },
+                        EitherOrBoth::Both(z, _) if !wants_z_level(options, z) => BoundType::None,
+                        EitherOrBoth::Both(z, _) => {
+                            let full_map = || {
+                                BoundType::Both((
+                                    BoundingBox::for_full_map(&base),
+                                    BoundingBox::for_full_map(&head),
+                                ))
+                            };
+                            if options.full_render {
+                                return full_map();
+                            }
+                            match get_diff_bounding_box(&base, &head, z, config.padding) {
+                                Some(bounds) => BoundType::Both(bounds),
+                                None if options.all_z_levels => full_map(),
+                                None => BoundType::None,
+                            }
+                        }
+                        EitherOrBoth::Left(z) | EitherOrBoth::Right(z)
+                            if !wants_z_level(options, z) =>
+                        {
+                            BoundType::None
+                        }
                         EitherOrBoth::Left(_base_only) => BoundType::OnlyBase,
                         EitherOrBoth::Right(_head_only) => BoundType::OnlyHead,
                     })
                     .collect::<Vec<_>>();
+                let cropped_to = limit_regions(&mut diffs, config);
                 let before = MapWithRegions {
                     map: base,
                     bounding_boxes: diffs.clone(),
+                    cropped_to,
                 };
                 let after = MapWithRegions {
                     map: head,
                     bounding_boxes: diffs,
+                    cropped_to,
                 };
-                returned_maps.insert(map_name, (Ok(before), Some(after)));
-                Ok(())
+                returned_maps.insert(map_name, Ok((before, after)));
             }
             (Err(e), _) => {
-                returned_maps.insert(map_name, (Err(e), None));
-                Ok(())
+                returned_maps.insert(map_name, Err(e.wrap_err("Parsing base version")));
             }
-            (_, Err(e)) => Err(e), //Fails on head parse fail
-        }?; //Stop the entire thing if head parse fails
+            (_, Err(e)) => {
+                returned_maps.insert(map_name, Err(e.wrap_err("Parsing head version")));
+            }
+        }
     }
 
-    Ok(returned_maps)
+    returned_maps
 }
 
 pub struct RenderingContext {
@@ -334,44 +496,105 @@ pub enum MapType {
 }
 
 pub type RenderedMaps = IndexMap<PathBuf, Vec<u8>, ahash::RandomState>;
+/// Why a map couldn't be rendered, by map name
+pub type RenderFailures = IndexMap<String, String, ahash::RandomState>;
+/// What SpacemanDMM reported while rendering, such as missing icon states, by map name
+pub type RenderWarnings = IndexMap<String, BTreeSet<String>, ahash::RandomState>;
+
+#[derive(Default)]
+pub struct RegionRenders {
+    pub images: RenderedMaps,
+    pub failures: RenderFailures,
+    /// Maps left out because a limit was reached
+    pub skipped: RenderFailures,
+    pub warnings: RenderWarnings,
+}
+
+/// Pixels are estimated from the tile count, rendered tiles are 32x32 pixels
+const TILE_PIXELS: u64 = 32 * 32;
+
+/// Limits shared by all renders of a job
+pub struct RenderBudgets {
+    pub pixels: Budget,
+    pub output: Budget,
+}
 
+impl RenderBudgets {
+    pub fn new(limits: &crate::LimitsConfig) -> Self {
+        Self {
+            pixels: Budget::new("rendered pixels", limits.max_rendered_pixels),
+            output: Budget::new("bytes of output", limits.max_output_bytes),
+        }
+    }
+
+    /// What was left out because a budget ran out, for the check summary
+    pub fn notices(&self) -> impl Iterator<Item = String> + '_ {
+        self.pixels.notice().into_iter().chain(self.output.notice())
+    }
+}
+
+/// Renders every map on its own, a failing map doesn't affect the others
 pub fn render_map_regions<'a, 'b, M>(
     context: &RenderingContext,
     maps: M, //&[(&str, &MapWithRegions)],
-    render_passes: &[Box<dyn RenderPass>],
+    render_passes: &RenderPasses,
     (output_dir, blob_client): (&Path, Azure),
     filename: &str,
-    errors: &RenderingErrors,
     map_type: MapType,
-) -> Result<RenderedMaps>
+    (cancel, budgets): (&CancelToken, &RenderBudgets),
+) -> RegionRenders
 where
     M: ParallelIterator<Item = (&'a str, &'b MapWithRegions)>,
 {
     let objtree = &context.obj_tree;
     let icon_cache = &context.icon_cache;
     let results = maps
-        .map(|(map_name, map)| -> Result<RenderedMaps> {
-            render_map_region(
-                map_name,
-                map,
-                map_type,
-                (objtree, icon_cache, errors, render_passes),
-                (output_dir, blob_client.clone(), filename),
-            )
+        .map(|(map_name, map)| {
+            let errors = RenderingErrors::default();
+            // Maps still waiting for a thread are skipped once the job is cancelled
+            let result = cancel.check().and_then(|_| {
+                render_map_region(
+                    map_name,
+                    map,
+                    map_type,
+                    (
+                        objtree,
+                        icon_cache,
+                        &errors,
+                        render_passes.for_map(map_name),
+                    ),
+                    (output_dir, blob_client.clone(), filename),
+                    budgets,
+                )
+            });
+            let warnings = errors
+                .into_inner()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            (map_name, result, warnings)
         })
         .collect::<Vec<_>>();
 
-    results.iter().for_each(|res| {
-        if let Err(e) = res {
-            tracing::error!("{e:?}") //errors please
+    let mut renders = RegionRenders::default();
+    for (map_name, result, warnings) in results {
+        match result {
+            Ok(images) => renders.images.extend(images),
+            Err(e) if is_limit_reached(&e) => {
+                renders.skipped.insert(map_name.to_owned(), e.to_string());
+            }
+            Err(e) => {
+                tracing::error!("{e:?}");
+                renders
+                    .failures
+                    .insert(map_name.to_owned(), format!("{e:?}"));
+            }
         }
-    });
-
-    Ok(results
-        .into_iter()
-        .filter_map(|thing| thing.ok())
-        .flatten()
-        .collect::<RenderedMaps>())
+        if !warnings.is_empty() {
+            renders
+                .warnings
+                .insert(map_name.to_owned(), warnings.into_iter().collect());
+        }
+    }
+    renders
 }
 
 fn render_map_region(
@@ -385,6 +608,7 @@ fn render_map_region(
         &[Box<dyn RenderPass>],
     ),
     (output_dir, blob_client, filename): (&Path, Azure, &str),
+    budgets: &RenderBudgets,
 ) -> Result<RenderedMaps> {
     let mut return_map: RenderedMaps = Default::default();
     for z_level in 0..map.map.dim_z() {
@@ -399,6 +623,9 @@ fn render_map_region(
                     MapType::Base => bounds.0,
                     MapType::Head => bounds.1,
                 };
+                if !budgets.pixels.take(bound.area() as u64 * TILE_PIXELS) {
+                    return Err(budgets.pixels.error());
+                }
                 Some(
                     render_map(
                         objtree,
@@ -414,6 +641,9 @@ fn render_map_region(
             }
             (MapType::Head, BoundType::OnlyHead) => {
                 let bounds = BoundingBox::for_full_map(&map.map);
+                if !budgets.pixels.take(bounds.area() as u64 * TILE_PIXELS) {
+                    return Err(budgets.pixels.error());
+                }
                 Some(
                     render_map(
                         objtree,
@@ -449,20 +679,30 @@ fn render_map_region(
             return_map.insert(directory.to_path_buf(), compressed_image);
         }
     }
+    let total_bytes = return_map
+        .values()
+        .map(|image| image.len() as u64)
+        .sum::<u64>();
+    if !budgets.output.take(total_bytes) {
+        return Err(budgets.output.error());
+    }
+
+    let _upload_timer = metrics::time_stage("upload");
     return_map.iter().for_each(|(directory, compressed_image)| {
         if let Some(ref blob_client) = blob_client {
-            if let Err(e) =
-                write_to_azure(directory, blob_client.clone(), compressed_image.as_slice())
-                    .wrap_err("Sending image to azure")
+            match write_to_azure(directory, blob_client.clone(), compressed_image.as_slice())
+                .wrap_err("Sending image to azure")
             {
-                tracing::error!("{e:?}")
+                Ok(()) => metrics::image_stored(compressed_image.len()),
+                Err(e) => tracing::error!("{e:?}"),
             };
             tracing::debug!("Sent to azure: {map_name} {directory:?}");
         } else {
-            if let Err(e) = write_to_file(directory, compressed_image.as_slice())
+            match write_to_file(directory, compressed_image.as_slice())
                 .wrap_err("Writing image to file")
             {
-                tracing::error!("{e:?}")
+                Ok(()) => metrics::image_stored(compressed_image.len()),
+                Err(e) => tracing::error!("{e:?}"),
             };
             tracing::debug!("Wrote to file: {map_name} {directory:?}");
         }
@@ -471,7 +711,14 @@ fn render_map_region(
     Ok(return_map)
 }
 
-pub fn render_diffs(before: RenderedMaps, after: RenderedMaps, blob_client: Azure) {
+/// Returns the paths of the diff images that were written, diffs of maps that changed size or that
+/// don't fit in the output budget are left out
+pub fn render_diffs(
+    before: RenderedMaps,
+    after: RenderedMaps,
+    blob_client: Azure,
+    output: &Budget,
+) -> HashSet<PathBuf> {
     let res = before
         .par_iter()
         .filter_map(|before| {
@@ -511,35 +758,43 @@ pub fn render_diffs(before: RenderedMaps, after: RenderedMaps, blob_client: Azur
         )
         .collect::<Vec<_>>();
 
-    res.iter()
+    let upload_timer = metrics::time_stage("upload");
+    let written = res
+        .iter()
         .filter_map(|item| item.as_ref().ok())
-        .for_each(|(final_path, image)| {
-            if image.is_empty() {
-                return;
-            }
-            if let Some(client) = blob_client.clone() {
-                if let Err(e) = write_to_azure(final_path, client, image.as_slice())
+        .filter(|(_, image)| !image.is_empty() && output.take(image.len() as u64))
+        .filter_map(|(final_path, image)| {
+            let result = if let Some(client) = blob_client.clone() {
+                tracing::debug!("Sending to azure: {final_path:?}");
+                write_to_azure(final_path, client, image.as_slice())
                     .wrap_err("Sending image to azure")
-                {
-                    tracing::error!("{e:?}")
-                };
-                tracing::debug!("Sent to azure: {final_path:?}");
-            } else if let Err(e) =
+            } else {
+                tracing::debug!("Writing to file: {final_path:?}");
                 write_to_file(final_path, image.as_slice()).wrap_err("Writing image to file")
-            {
-                tracing::error!("{e:?}");
-                tracing::debug!("Written to file: {final_path:?}");
+            };
+            match result {
+                Ok(()) => {
+                    metrics::image_stored(image.len());
+                    Some(final_path.clone())
+                }
+                Err(e) => {
+                    tracing::error!("{e:?}");
+                    None
+                }
             }
-        });
+        })
+        .collect();
+    drop(upload_timer);
 
     res.into_iter().for_each(|res| {
         if let Err(e) = res {
             tracing::error!("{e:?}");
         }
     });
+    written
 }
 
-fn write_to_azure<P: AsRef<Path>>(
+pub fn write_to_azure<P: AsRef<Path>>(
     path: P,
     client: std::sync::Arc<MicrosoftAzure>,
     compressed_image: &[u8],
@@ -570,7 +825,7 @@ fn compress_image(image: image::RgbaImage) -> Result<Vec<u8>> {
     Ok(vec)
 }
 
-fn write_to_file<P: AsRef<Path>>(path: P, compressed_image: &[u8]) -> Result<()> {
+pub fn write_to_file<P: AsRef<Path>>(path: P, compressed_image: &[u8]) -> Result<()> {
     std::fs::create_dir_all(
         path.as_ref()
             .parent()
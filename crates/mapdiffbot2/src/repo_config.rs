@@ -0,0 +1,121 @@
+use diffbot_lib::repo_config::{MergedConfig, PathFilter};
+use eyre::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+pub const REPO_CONFIG_FILE: &str = "mapdiff.toml";
+
+/// Per-repository settings, read from `mapdiff.toml` in the repository root of the base commit
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapConfig {
+    /// Top level keys the PR's own `mapdiff.toml` may override
+    pub pr_overridable: Vec<String>,
+    /// Only maps matching one of these globs are rendered, everything if empty
+    pub include: Vec<String>,
+    /// Maps matching one of these globs are never rendered
+    pub exclude: Vec<String>,
+    pub include_pass: String,
+    pub exclude_pass: String,
+    /// Render pass overrides for specific maps or directories, the last matching entry wins
+    pub overrides: Vec<PassOverride>,
+    /// Rendered regions are cropped to at most this many tiles
+    pub max_width: Option<usize>,
+    pub max_height: Option<usize>,
+    /// How many tiles of context are added around a modified region
+    pub padding: usize,
+    /// Display names of z-levels, keyed by map path, first entry is z-level 1
+    pub z_names: HashMap<String, Vec<String>>,
+    pub lint: LintConfig,
+}
+
+impl Default for MapConfig {
+    fn default() -> Self {
+        Self {
+            pr_overridable: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            include_pass: "".to_owned(),
+            exclude_pass: "hide-space,hide-invisible,random".to_owned(),
+            overrides: Vec::new(),
+            max_width: None,
+            max_height: None,
+            padding: 2,
+            z_names: HashMap::new(),
+            lint: LintConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PassOverride {
+    /// Glob matched against the map path, e.g. `_maps/shuttles/**`
+    pub path: String,
+    /// Falls back to the top level passes if unset
+    pub include_pass: Option<String>,
+    pub exclude_pass: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    /// Typepaths (and their subtypes) that should never be placed on a map
+    pub forbidden_paths: Vec<String>,
+    /// Warn on identical objects stacked on the same tile
+    pub duplicate_objects: bool,
+}
+
+impl MapConfig {
+    /// Reads the raw config file from the given checkout, if there is one
+    pub fn read_file(repo_dir: &Path) -> Result<Option<String>> {
+        let config_path = repo_dir.join(REPO_CONFIG_FILE);
+        if !config_path.exists() {
+            return Ok(None);
+        }
+        let mut config_str = String::new();
+        std::fs::File::open(&config_path)
+            .wrap_err_with(|| format!("Opening {REPO_CONFIG_FILE}"))?
+            .read_to_string(&mut config_str)
+            .wrap_err_with(|| format!("Reading {REPO_CONFIG_FILE}"))?;
+        Ok(Some(config_str))
+    }
+
+    pub fn from_merged(merged: &MergedConfig) -> Result<Self> {
+        let config: Self = merged
+            .deserialize()
+            .wrap_err_with(|| format!("Parsing {REPO_CONFIG_FILE}"))?;
+        config.map_filter()?;
+        for item in config.overrides.iter() {
+            item.matcher()?;
+        }
+        Ok(config)
+    }
+
+    pub fn map_filter(&self) -> Result<PathFilter> {
+        PathFilter::new(&self.include, &self.exclude)
+    }
+
+    /// Returns the display name for the given zero indexed z-level
+    pub fn z_level_name(&self, map_name: &str, z_level: usize) -> String {
+        match self
+            .z_names
+            .get(map_name)
+            .and_then(|names| names.get(z_level))
+        {
+            Some(name) => format!("Z-level: {} - {name}", z_level + 1),
+            None => format!("Z-level: {}", z_level + 1),
+        }
+    }
+}
+
+impl PassOverride {
+    pub fn matcher(&self) -> Result<GlobMatcher> {
+        Ok(Glob::new(&self.path)
+            .wrap_err_with(|| format!("Invalid glob {:?}", self.path))?
+            .compile_matcher())
+    }
+}
@@ -1,31 +1,108 @@
 use std::path::PathBuf;
 use std::time::Duration;
 
+use super::git_operations::clone_repo;
 use super::job_processor::do_job;
+use diffbot_lib::github::github_types::InstallationRepository;
 use diffbot_lib::job::types::{Job, JobType};
+use octocrab::models::InstallationId;
+use secrecy::ExposeSecret;
 
-use diffbot_lib::tracing;
+use diffbot_lib::{
+    job::{
+        shutdown,
+        tracker::{self, JobResult},
+    },
+    metrics, tracing,
+};
 
 use super::Azure;
 
+pub type Preclone = (InstallationId, InstallationRepository);
+
 pub async fn handle_jobs<S: AsRef<str>>(
     name: S,
     job_receiver: flume::Receiver<JobType>,
     blob_client: Azure,
+    pool: Option<mysql_async::Pool>,
+    preclones: flume::Sender<Preclone>,
 ) {
     loop {
-        match job_receiver.recv_async().await {
+        let received = job_receiver.recv_async().await;
+        if shutdown::is_shutting_down() {
+            tracing::info!("Shutting down, leaving the queue to the checkpoint");
+            return;
+        }
+        match received {
             Ok(job_type) => match job_type {
                 JobType::GithubJob(job) => {
                     job_handler(name.as_ref(), *job, blob_client.clone()).await
                 }
-                JobType::CleanupJob => garbage_collect_all_repos().await,
+                JobType::CleanupJob => {
+                    garbage_collect_all_repos().await;
+                    if let Some(ref pool) = pool {
+                        prune_expired_images(pool, blob_client.clone()).await;
+                    }
+                }
+                JobType::CloneJob(installation, repository) => {
+                    if let Err(err) = preclones.send_async((installation, repository)).await {
+                        tracing::error!("{err}");
+                    }
+                }
+                JobType::RemoveRepositoryJob(installation, repository) => {
+                    remove_repository_data(installation, repository, blob_client.clone()).await
+                }
+                JobType::RemoveOwnerJob(owner) => {
+                    if let Some(owner_dir) = owner_dir(&owner) {
+                        remove_dir(owner_dir).await;
+                    }
+                }
             },
             Err(err) => tracing::error!("{err}"),
         }
     }
 }
 
+/// Deletes the images of closed PRs that are past their installation's retention days
+async fn prune_expired_images(pool: &mysql_async::Pool, blob_client: Azure) {
+    let expired = match diffbot_lib::db::expired_jobs(pool).await {
+        Ok(expired) => expired,
+        Err(e) => {
+            tracing::error!("Looking up expired images: {e:?}");
+            return;
+        }
+    };
+    if !expired.is_empty() {
+        tracing::info!("Deleting the images of {} expired job(s)", expired.len());
+    }
+
+    for job in expired {
+        let images: PathBuf = [
+            "images",
+            &job.repo_id.to_string(),
+            &job.check_id.to_string(),
+        ]
+        .iter()
+        .collect();
+        match blob_client_for(InstallationId(job.installation_id), blob_client.clone()) {
+            Ok(Some(client)) => {
+                if let Err(e) = delete_blobs(client, &images).await {
+                    tracing::error!("Deleting blobs of check run {}: {e:?}", job.check_id);
+                    continue;
+                }
+            }
+            Ok(None) => remove_dir(images).await,
+            Err(e) => {
+                tracing::error!("{e:?}");
+                continue;
+            }
+        }
+        if let Err(e) = diffbot_lib::db::mark_processed(pool, job.check_id).await {
+            tracing::error!("{e:?}");
+        }
+    }
+}
+
 async fn garbage_collect_all_repos() {
     use eyre::Result;
     use path_absolutize::Absolutize;
@@ -103,6 +180,157 @@ async fn garbage_collect_all_repos() {
     }
 }
 
+/// Where the repository is cloned to, `None` if the name could point outside of `./repos`
+fn repo_dir(full_name: &str) -> Option<PathBuf> {
+    let (owner, repo) = full_name.split_once('/')?;
+    Some(owner_dir(owner)?.join(valid_path_part(repo)?))
+}
+
+fn owner_dir(owner: &str) -> Option<PathBuf> {
+    Some(PathBuf::from("./repos").join(valid_path_part(owner)?))
+}
+
+fn valid_path_part(part: &str) -> Option<&str> {
+    let valid = !part.is_empty() && part != "." && part != ".." && !part.contains(['/', '\\']);
+    valid.then_some(part)
+}
+
+/// Uses the installation's storage container if its policy has one
+fn blob_client_for(installation: InstallationId, blob_client: Azure) -> eyre::Result<Azure> {
+    let conf = crate::read_config();
+    match (
+        &conf.azure_blobs,
+        conf.policy.storage_container(installation.0),
+    ) {
+        (Some(azure), Some(container)) => Ok(Some(
+            crate::azure_client(azure, container)
+                .map_err(|e| eyre::eyre!("Connecting to storage container {container}: {e:?}"))?,
+        )),
+        _ => Ok(blob_client),
+    }
+}
+
+/// Clones newly installed repositories one at a time, beside the job queue so PRs don't wait on them
+pub async fn handle_preclones(preclones: flume::Receiver<Preclone>) {
+    while let Ok((installation, repository)) = preclones.recv_async().await {
+        if shutdown::is_shutting_down() {
+            return;
+        }
+        preclone_repo(installation, repository).await;
+    }
+}
+
+async fn preclone_repo(installation: InstallationId, repository: InstallationRepository) {
+    let Some(repo_dir) = repo_dir(&repository.full_name) else {
+        tracing::warn!("Not cloning {:?}, invalid name", repository.full_name);
+        return;
+    };
+    if repo_dir.exists() {
+        return;
+    }
+
+    let token = match octocrab::instance()
+        .installation_and_token(installation)
+        .await
+    {
+        Ok((_, token)) => token,
+        Err(e) => {
+            tracing::error!("Getting a token to clone {}: {e:?}", repository.full_name);
+            return;
+        }
+    };
+    let url = format!(
+        "https://x-access-token:{}@github.com/{}",
+        token.expose_secret(),
+        repository.full_name
+    );
+
+    tracing::info!("Cloning {} ahead of its first PR", repository.full_name);
+    let _clone_timer = metrics::time_stage("clone");
+    // Cloned elsewhere and moved into place once finished, a job for the repository may start
+    // meanwhile and would mistake a partial clone for a finished one
+    let staging_dir = PathBuf::from("./preclones").join(&repository.full_name);
+    let result = actix_web::rt::task::spawn_blocking(move || -> eyre::Result<bool> {
+        if staging_dir.exists() {
+            std::fs::remove_dir_all(&staging_dir)?;
+        }
+        std::fs::create_dir_all(&staging_dir)?;
+        let cloned = clone_repo(&url, &staging_dir).and_then(|()| {
+            if repo_dir.exists() {
+                return Ok(false);
+            }
+            if let Some(owner_dir) = repo_dir.parent() {
+                std::fs::create_dir_all(owner_dir)?;
+            }
+            std::fs::rename(&staging_dir, &repo_dir)?;
+            Ok(true)
+        });
+        if !matches!(cloned, Ok(true)) {
+            _ = std::fs::remove_dir_all(&staging_dir);
+        }
+        cloned
+    })
+    .await;
+    match result {
+        Ok(Ok(true)) => tracing::info!("Cloned {}", repository.full_name),
+        Ok(Ok(false)) => tracing::info!("{} was cloned by a job first", repository.full_name),
+        Ok(Err(e)) => tracing::error!("Cloning {}: {e:?}", repository.full_name),
+        Err(e) => tracing::error!("Join Handle error: {e}"),
+    }
+}
+
+/// Deletes the clone, the rendered images and the blobs of a repository
+async fn remove_repository_data(
+    installation: InstallationId,
+    repository: InstallationRepository,
+    blob_client: Azure,
+) {
+    if let Some(repo_dir) = repo_dir(&repository.full_name) {
+        remove_dir(repo_dir).await;
+    }
+
+    let images: PathBuf = ["images", &repository.id.to_string()].iter().collect();
+    match blob_client_for(installation, blob_client) {
+        Ok(Some(client)) => match delete_blobs(client, &images).await {
+            Ok(deleted) => tracing::info!("Deleted {deleted} blob(s) of {}", repository.full_name),
+            Err(e) => tracing::error!("Deleting blobs of {}: {e:?}", repository.full_name),
+        },
+        Ok(None) => remove_dir(images).await,
+        Err(e) => tracing::error!("{e:?}"),
+    }
+}
+
+async fn delete_blobs(
+    client: std::sync::Arc<object_store::azure::MicrosoftAzure>,
+    prefix: &std::path::Path,
+) -> eyre::Result<usize> {
+    use futures::{StreamExt, TryStreamExt};
+    use object_store::ObjectStore;
+
+    let prefix = object_store::path::Path::from_iter(
+        prefix
+            .iter()
+            .map(|part| part.to_string_lossy().into_owned()),
+    );
+    let locations = client
+        .list(Some(&prefix))
+        .map_ok(|meta| meta.location)
+        .boxed();
+    let deleted = client
+        .delete_stream(locations)
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(deleted.len())
+}
+
+async fn remove_dir(path: PathBuf) {
+    match diffbot_lib::async_fs::remove_dir_all(&path).await {
+        Ok(()) => tracing::info!("Removed {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => tracing::error!("Removing {}: {e}", path.display()),
+    }
+}
+
 async fn job_handler(name: &str, job: Job, blob_client: Azure) {
     let (repo, pull_request, check_run) =
         (job.repo.clone(), job.pull_request, job.check_run.clone());
@@ -112,14 +340,48 @@ async fn job_handler(name: &str, job: Job, blob_client: Azure) {
         check_run.id()
     );
 
+    let Some((cancel, token)) = tracker::started(&job) else {
+        tracing::info!(
+            "[{}#{pull_request}] [{}] Cancelled while queued, skipping",
+            repo.full_name(),
+            check_run.id()
+        );
+        return;
+    };
+
     _ = check_run.mark_started().await;
+    let installation = job.installation;
 
-    let output = actix_web::rt::time::timeout(
-        Duration::from_secs(7200),
-        actix_web::rt::task::spawn_blocking(move || do_job(job, blob_client)),
+    let blob_client = match blob_client_for(installation, blob_client) {
+        Ok(blob_client) => blob_client,
+        Err(e) => {
+            let fuckup = format!("{e:?}");
+            tracing::error!("{fuckup}");
+            _ = check_run.mark_failed(&fuckup).await;
+            tracker::finished(check_run.id(), JobResult::Failed(fuckup));
+            return;
+        }
+    };
+    let timeout = crate::read_config().job_timeout(&repo.full_name());
+
+    let output = tracker::until_cancelled(
+        cancel,
+        actix_web::rt::time::timeout(
+            timeout,
+            actix_web::rt::task::spawn_blocking(move || do_job(job, blob_client, token)),
+        ),
     )
     .await;
 
+    let Some(output) = output else {
+        tracing::info!(
+            "[{}#{pull_request}] [{}] Cancelled",
+            repo.full_name(),
+            check_run.id()
+        );
+        return;
+    };
+
     tracing::info!(
         "[{}#{pull_request}] [{}] Finished",
         repo.full_name(),
@@ -128,8 +390,9 @@ async fn job_handler(name: &str, job: Job, blob_client: Azure) {
 
     let output = {
         if output.is_err() {
-            tracing::error!("Job timed out!");
-            _ = check_run.mark_failed("Job timed out after 1 hours!").await;
+            let message = tracker::timed_out(check_run.id(), timeout);
+            tracing::error!("{message}");
+            _ = check_run.mark_failed(&message).await;
             return;
         }
         output.unwrap()
@@ -144,6 +407,7 @@ async fn job_handler(name: &str, job: Job, blob_client: Azure) {
         };
         tracing::error!("Join Handle error: {fuckup}");
         _ = check_run.mark_failed(&fuckup).await;
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
         return;
     }
 
@@ -152,15 +416,24 @@ async fn job_handler(name: &str, job: Job, blob_client: Azure) {
         let fuckup = format!("{e:?}");
         tracing::error!("Other rendering error: {fuckup}");
         _ = check_run.mark_failed(&fuckup).await;
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
         return;
     }
 
     let output = output.unwrap();
-    if let Err(e) = diffbot_lib::job::runner::handle_output(output, &check_run, name).await {
+    let timer = tracker::enter_stage(check_run.id(), "github_output");
+    let result = diffbot_lib::job::runner::handle_output(output, &check_run, name).await;
+    drop(timer);
+    if let Err(e) = result {
         let fuckup = format!("{e:?}");
         tracing::error!("Output upload error: {fuckup}");
         _ = check_run
             .mark_failed(&format!("Failed to upload job output: {fuckup}"))
             .await;
-    };
+        tracker::finished(check_run.id(), JobResult::Failed(fuckup));
+    } else {
+        tracker::finished(check_run.id(), JobResult::Succeeded);
+    }
+
+    metrics::update_rate_limit(installation).await;
 }